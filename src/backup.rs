@@ -0,0 +1,90 @@
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use tmail::CreateSpec;
+
+/// One alias from a backup file. JSON backups are the output of
+/// `tmail masked list --json`; CSV backups need a header row with
+/// `description` and `forDomain` (or `for_domain`/`domain`) columns, and
+/// optionally `url`.
+#[derive(Deserialize, Debug)]
+struct BackupEntry {
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(rename = "forDomain", alias = "for_domain", alias = "domain", default)]
+    for_domain: Option<String>,
+    #[serde(default)]
+    url: Option<String>,
+}
+
+impl From<BackupEntry> for CreateSpec {
+    fn from(entry: BackupEntry) -> Self {
+        CreateSpec {
+            description: entry.description.filter(|d| !d.is_empty()),
+            for_domain: entry.for_domain.filter(|d| !d.is_empty()),
+            url: entry.url.filter(|u| !u.is_empty()),
+            email_prefix: None,
+            state: None,
+        }
+    }
+}
+
+pub fn read_backup(path: &Path) -> Result<Vec<CreateSpec>, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    let is_csv = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"));
+    if is_csv {
+        parse_csv(&content)
+    } else {
+        parse_json(&content)
+    }
+}
+
+fn parse_json(content: &str) -> Result<Vec<CreateSpec>, String> {
+    let entries: Vec<BackupEntry> =
+        serde_json::from_str(content).map_err(|e| format!("Invalid JSON backup: {}", e))?;
+    Ok(entries.into_iter().map(CreateSpec::from).collect())
+}
+
+fn parse_csv(content: &str) -> Result<Vec<CreateSpec>, String> {
+    let mut reader = csv::Reader::from_reader(content.as_bytes());
+    reader
+        .deserialize::<BackupEntry>()
+        .map(|row| {
+            row.map(CreateSpec::from)
+                .map_err(|e| format!("Invalid CSV backup: {}", e))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_json_list_output() {
+        let content = r#"[
+            {"id": "m1", "email": "a@fastmail.com", "state": "enabled",
+             "forDomain": "example.com", "description": "shop"},
+            {"id": "m2", "email": "b@fastmail.com", "description": ""}
+        ]"#;
+        let specs = parse_json(content).unwrap();
+        assert_eq!(specs.len(), 2);
+        assert_eq!(specs[0].description.as_deref(), Some("shop"));
+        assert_eq!(specs[0].for_domain.as_deref(), Some("example.com"));
+        assert_eq!(specs[1].description, None);
+    }
+
+    #[test]
+    fn test_parse_csv_with_quoted_fields() {
+        let content = "email,domain,description\n\
+                       a@fastmail.com,example.com,\"shop, online\"\n\
+                       b@fastmail.com,,newsletter\n";
+        let specs = parse_csv(content).unwrap();
+        assert_eq!(specs.len(), 2);
+        assert_eq!(specs[0].description.as_deref(), Some("shop, online"));
+        assert_eq!(specs[1].for_domain, None);
+    }
+}
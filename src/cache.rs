@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tmail::{AccountId, MaskedEmail};
+
+use crate::output::write_atomic;
+
+/// Last successful list per account, so `list` keeps working without network.
+#[derive(Serialize, Deserialize, Default)]
+struct Cache {
+    #[serde(default)]
+    accounts: HashMap<AccountId, CachedList>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CachedList {
+    /// Seconds since the Unix epoch when the list was fetched
+    pub fetched_at: u64,
+    pub emails: Vec<MaskedEmail>,
+    /// The `MaskedEmail` state the list reflects, the baseline for a sync
+    #[serde(default)]
+    pub state: Option<String>,
+}
+
+impl CachedList {
+    pub fn age_description(&self) -> String {
+        let secs = now().saturating_sub(self.fetched_at);
+        match secs {
+            0..=59 => "just now".to_string(),
+            60..=3599 => format!("{} min ago", secs / 60),
+            3600..=86399 => format!("{} h ago", secs / 3600),
+            _ => format!("{} days ago", secs / 86400),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn read(path: &Path) -> Cache {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+pub fn load(path: &Path, account_id: &AccountId) -> Option<CachedList> {
+    read(path).accounts.remove(account_id)
+}
+
+/// Best effort: a cache that can't be written shouldn't fail the command.
+/// Written atomically, so a crash never leaves a truncated cache behind.
+pub fn store(path: &Path, account_id: &AccountId, emails: &[MaskedEmail], state: &str) {
+    let mut cache = read(path);
+    cache.accounts.insert(
+        account_id.clone(),
+        CachedList {
+            fetched_at: now(),
+            emails: emails.to_vec(),
+            state: Some(state.to_string()),
+        },
+    );
+    if let Ok(content) = serde_json::to_string(&cache) {
+        let _ = write_atomic(path, &content);
+    }
+}
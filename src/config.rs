@@ -0,0 +1,376 @@
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tmail::AccountId;
+
+use crate::output::write_atomic;
+
+/// Schema version written by this build. Version 0 is the original
+/// unversioned `api_token` + `account_id` file; version 2 moved `proxy`
+/// into the `http` section.
+pub const CURRENT_VERSION: u32 = 2;
+
+/// How long to wait for another process to finish updating the config.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A config file given with `--config`, used instead of the config directory's.
+static CONFIG_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Use `path` as the config file for the rest of this run. Only the first
+/// call has any effect.
+pub fn set_config_file(path: PathBuf) {
+    let _ = CONFIG_FILE.set(path);
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub api_token: String,
+    pub account_id: AccountId,
+    /// Shell command whose stdout is the API token, used instead of a stored token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_command: Option<String>,
+    #[serde(default, skip_serializing_if = "HttpConfig::is_empty")]
+    pub http: HttpConfig,
+    /// Refuse to create a masked email without a description, even with a domain
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub require_description: bool,
+    /// Fields written by newer versions, kept so saving doesn't drop them
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Config {
+    pub fn new(api_token: String, account_id: AccountId) -> Self {
+        Self {
+            version: CURRENT_VERSION,
+            api_token,
+            account_id,
+            token_command: None,
+            http: HttpConfig::default(),
+            require_description: false,
+            extra: serde_json::Map::new(),
+        }
+    }
+}
+
+/// How to reach the API: the `"http"` object in the config file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HttpConfig {
+    /// HTTP(S) or SOCKS proxy URL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    /// Seconds to wait for a request before giving up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+    /// PEM file with an extra CA certificate to trust, e.g. a TLS-inspecting proxy's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_certificate: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+}
+
+impl HttpConfig {
+    pub fn is_empty(&self) -> bool {
+        self.proxy.is_none()
+            && self.timeout_secs.is_none()
+            && self.ca_certificate.is_none()
+            && self.user_agent.is_none()
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// There is no config file yet
+    NotLoggedIn,
+    NoConfigDir,
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, String),
+    TokenCommand(String),
+    /// Another process held the config lock for longer than `LOCK_TIMEOUT`
+    Locked(PathBuf),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::NotLoggedIn => write!(f, "Not logged in. Run 'tmail login' first."),
+            ConfigError::NoConfigDir => {
+                write!(f, "Could not find a config directory; set TMAIL_CONFIG_DIR")
+            }
+            ConfigError::Io(path, e) => write!(f, "Could not access {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(
+                f,
+                "Invalid config file {}: {}. Run 'tmail login' to recreate it.",
+                path.display(),
+                e
+            ),
+            ConfigError::TokenCommand(e) => write!(f, "token_command {}", e),
+            ConfigError::Locked(path) => write!(
+                f,
+                "Timed out waiting for {}; another tmail is updating the config",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Leaves out the token so it never ends up in logs.
+impl std::fmt::Debug for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Config")
+            .field("version", &self.version)
+            .field("api_token", &"<redacted>")
+            .field("account_id", &self.account_id)
+            .field("token_command", &self.token_command)
+            .field("http", &self.http)
+            .field("require_description", &self.require_description)
+            .finish_non_exhaustive()
+    }
+}
+
+/// `TMAIL_CONFIG_DIR` if set, else `$XDG_CONFIG_HOME/tmail`, else
+/// `~/.config/tmail`. Empty variables count as unset.
+fn resolve_config_dir(
+    tmail_dir: Option<OsString>,
+    xdg_config_home: Option<OsString>,
+    home: Option<PathBuf>,
+) -> Option<PathBuf> {
+    let non_empty = |v: Option<OsString>| v.filter(|v| !v.is_empty()).map(PathBuf::from);
+    non_empty(tmail_dir)
+        .or_else(|| non_empty(xdg_config_home).map(|dir| dir.join("tmail")))
+        .or_else(|| home.map(|home| home.join(".config").join("tmail")))
+}
+
+pub fn config_dir() -> Result<PathBuf, ConfigError> {
+    let config_dir = resolve_config_dir(
+        std::env::var_os("TMAIL_CONFIG_DIR"),
+        std::env::var_os("XDG_CONFIG_HOME"),
+        dirs::home_dir(),
+    )
+    .ok_or(ConfigError::NoConfigDir)?;
+    fs::create_dir_all(&config_dir).map_err(|e| ConfigError::Io(config_dir.clone(), e))?;
+    Ok(config_dir)
+}
+
+/// The `--config` file if one was given, else `config.json` in `config_dir`.
+pub fn config_path() -> Result<PathBuf, ConfigError> {
+    match CONFIG_FILE.get() {
+        Some(path) => Ok(path.clone()),
+        None => Ok(config_dir()?.join("config.json")),
+    }
+}
+
+/// `cache.json` in `config_dir`, or `<name>.cache.json` beside a `--config`
+/// file, so profiles for different accounts don't share a cache.
+pub fn cache_path() -> Result<PathBuf, ConfigError> {
+    match CONFIG_FILE.get() {
+        Some(path) => Ok(path.with_extension("cache.json")),
+        None => Ok(config_dir()?.join("cache.json")),
+    }
+}
+
+/// Upgrade an older config to the current schema in memory. The file is
+/// rewritten in the new shape the next time it's saved. Newer versions are
+/// left alone so an older binary never downgrades them.
+fn migrate(mut config: Config) -> Config {
+    if config.version == 0 {
+        // v0 -> v1: only the version field was added
+        config.version = 1;
+    }
+    if config.version == 1 {
+        // v1 -> v2: the top-level proxy moved into the http section
+        if let Some(serde_json::Value::String(proxy)) = config.extra.remove("proxy") {
+            config.http.proxy.get_or_insert(proxy);
+        }
+        config.version = 2;
+    }
+    config
+}
+
+fn parse(content: &str) -> Result<Config, serde_json::Error> {
+    serde_json::from_str(content).map(migrate)
+}
+
+/// Run a `token_command` and return its trimmed stdout, like git's
+/// credential helpers.
+fn run_token_command(command: &str) -> Result<String, String> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()
+        .map_err(|e| format!("could not run '{}': {}", command, e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!(
+            "'{}' failed ({}): {}",
+            command,
+            output.status,
+            stderr.trim()
+        ));
+    }
+    let token = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if token.is_empty() {
+        return Err(format!("'{}' printed no token", command));
+    }
+    Ok(token)
+}
+
+/// Load the config, running `token_command` if set. The token it prints is
+/// only held in memory for this process.
+pub fn load_config() -> Result<Config, ConfigError> {
+    let path = config_path()?;
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(ConfigError::NotLoggedIn)
+        }
+        Err(e) => return Err(ConfigError::Io(path, e)),
+    };
+    let mut config = parse(&content).map_err(|e| ConfigError::Parse(path, e.to_string()))?;
+    if let Some(command) = &config.token_command {
+        config.api_token = run_token_command(command).map_err(ConfigError::TokenCommand)?;
+    }
+    Ok(config)
+}
+
+/// Take an exclusive lock on `path`, polling until `timeout` runs out. The
+/// lock is released when the returned file is dropped.
+fn lock_file(path: &Path, timeout: Duration) -> Result<fs::File, ConfigError> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+    let start = Instant::now();
+    loop {
+        match file.try_lock() {
+            Ok(()) => return Ok(file),
+            Err(fs::TryLockError::WouldBlock) if start.elapsed() < timeout => {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            Err(fs::TryLockError::WouldBlock) => {
+                return Err(ConfigError::Locked(path.to_path_buf()))
+            }
+            Err(fs::TryLockError::Error(e)) => {
+                return Err(ConfigError::Io(path.to_path_buf(), e))
+            }
+        }
+    }
+}
+
+/// Read, change and save the config while holding a `.lock` file beside
+/// it, so concurrent runs can't interleave writes or lose each other's
+/// changes. `change` gets `None` when there is no config yet or it can't be
+/// parsed; `token_command` isn't run.
+pub fn update_config(change: impl FnOnce(Option<Config>) -> Config) -> Result<(), ConfigError> {
+    let path = config_path()?;
+    let _lock = lock_file(&path.with_extension("lock"), LOCK_TIMEOUT)?;
+    let current = fs::read_to_string(&path).ok().and_then(|content| parse(&content).ok());
+    let config = change(current);
+    let content = serde_json::to_string_pretty(&config).expect("Config always serializes");
+    write_atomic(&path, &content).map_err(|e| ConfigError::Io(path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrates_v0_config() {
+        let config = parse(r#"{"api_token": "tok", "account_id": "u1"}"#).unwrap();
+        assert_eq!(config.version, CURRENT_VERSION);
+        assert_eq!(config.api_token, "tok");
+
+        let saved: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&config).unwrap()).unwrap();
+        assert_eq!(saved["version"], CURRENT_VERSION);
+        assert_eq!(saved["account_id"], "u1");
+    }
+
+    #[test]
+    fn test_migrates_v1_proxy_into_http() {
+        let content = r#"{"version": 1, "account_id": "u1", "proxy": "socks5://host:1080"}"#;
+        let config = parse(content).unwrap();
+        assert_eq!(config.version, CURRENT_VERSION);
+        assert_eq!(config.http.proxy.as_deref(), Some("socks5://host:1080"));
+
+        let saved: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&config).unwrap()).unwrap();
+        assert_eq!(saved["http"]["proxy"], "socks5://host:1080");
+        assert!(saved.get("proxy").is_none());
+        assert!(saved["http"].get("timeout_secs").is_none());
+    }
+
+    #[test]
+    fn test_preserves_unknown_fields() {
+        let content = r#"{
+            "version": 7,
+            "api_token": "tok",
+            "account_id": "u1",
+            "profiles": {"work": {"account_id": "u2"}}
+        }"#;
+        let config = parse(content).unwrap();
+        assert_eq!(config.version, 7);
+
+        let saved: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&config).unwrap()).unwrap();
+        assert_eq!(saved["version"], 7);
+        assert_eq!(saved["profiles"]["work"]["account_id"], "u2");
+    }
+
+    #[test]
+    fn test_resolve_config_dir() {
+        let home = Some(PathBuf::from("/home/me"));
+        assert_eq!(
+            resolve_config_dir(None, None, home.clone()),
+            Some(PathBuf::from("/home/me/.config/tmail"))
+        );
+        assert_eq!(
+            resolve_config_dir(None, Some("/xdg".into()), home.clone()),
+            Some(PathBuf::from("/xdg/tmail"))
+        );
+        assert_eq!(
+            resolve_config_dir(Some("/custom".into()), Some("/xdg".into()), home.clone()),
+            Some(PathBuf::from("/custom"))
+        );
+        assert_eq!(
+            resolve_config_dir(Some("".into()), Some("".into()), home),
+            Some(PathBuf::from("/home/me/.config/tmail"))
+        );
+    }
+
+    #[test]
+    fn test_lock_file_times_out() {
+        let path = std::env::temp_dir().join(format!("tmail-lock-test-{}", std::process::id()));
+        let held = lock_file(&path, Duration::ZERO).unwrap();
+        let err = lock_file(&path, Duration::from_millis(100)).unwrap_err();
+        assert!(matches!(err, ConfigError::Locked(_)), "{:?}", err);
+
+        drop(held);
+        assert!(lock_file(&path, Duration::ZERO).is_ok());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_token_command() {
+        let config = parse(r#"{"version": 1, "account_id": "u1", "token_command": "pass fastmail"}"#)
+            .unwrap();
+        assert!(config.api_token.is_empty());
+        assert_eq!(config.token_command.as_deref(), Some("pass fastmail"));
+
+        assert_eq!(run_token_command("printf ' tok\\n'").unwrap(), "tok");
+        let err = run_token_command("echo denied >&2; exit 3").unwrap_err();
+        assert!(err.contains("denied"));
+        assert!(run_token_command("true").is_err());
+    }
+}
@@ -1,19 +1,46 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[cfg(all(feature = "tls-native", feature = "tls-rustls"))]
+compile_error!("features `tls-native` and `tls-rustls` are mutually exclusive");
+#[cfg(not(any(feature = "tls-native", feature = "tls-rustls")))]
+compile_error!("enable one of the `tls-native` or `tls-rustls` features");
 
 const FASTMAIL_SESSION_URL: &str = "https://api.fastmail.com/jmap/session";
 const FASTMAIL_API_URL: &str = "https://api.fastmail.com/jmap/api/";
 const JMAP_CORE_CAPABILITY: &str = "urn:ietf:params:jmap:core";
 const MASKED_EMAIL_CAPABILITY: &str = "https://www.fastmail.com/dev/maskedemail";
+const JMAP_MAIL_CAPABILITY: &str = "urn:ietf:params:jmap:mail";
+const JMAP_SUBMISSION_CAPABILITY: &str = "urn:ietf:params:jmap:submission";
+const DEFAULT_MAX_RETRIES: u32 = 2;
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// Starts the tag `create_masked_email_with` puts in a description so a
+/// retried create can find the alias.
+const RETRY_TAG: &str = "tmail";
+/// Masked emails fetched per request by `iter_masked_emails`.
+const PAGE_SIZE: usize = 256;
 
+/// Everything this crate can fail with. It's `Send + Sync + 'static`, so it
+/// converts into `anyhow::Error` or `Box<dyn Error + Send + Sync>` with `?`
+/// and can be wrapped in a `thiserror` enum with `#[from]`; a test keeps it so.
 #[derive(Debug)]
 pub enum FastmailError {
     Http(String),
+    /// The token was rejected (401 or 403)
     Auth(u16, String),
+    /// Any other unsuccessful HTTP status, e.g. a 5xx outage or a 429
+    Status(u16, String),
     Api(String),
     Parse(String),
-    MissingCapability,
+    /// The server doesn't offer this capability URN to the token
+    MissingCapability(String),
     NotFound(String),
+    Build(String),
+    InvalidInput(String),
 }
 
 impl std::fmt::Display for FastmailError {
@@ -21,20 +48,93 @@ impl std::fmt::Display for FastmailError {
         match self {
             FastmailError::Http(e) => write!(f, "HTTP error: {}", e),
             FastmailError::Auth(status, body) => write!(f, "Auth failed ({}): {}", status, body),
+            FastmailError::Status(status, body) => write!(f, "HTTP {}: {}", status, body),
             FastmailError::Api(e) => write!(f, "API error: {}", e),
             FastmailError::Parse(e) => write!(f, "Parse error: {}", e),
-            FastmailError::MissingCapability => write!(f, "Masked email capability not found"),
+            FastmailError::MissingCapability(urn) => write!(f, "Capability not found: {}", urn),
             FastmailError::NotFound(e) => write!(f, "Not found: {}", e),
+            FastmailError::Build(e) => write!(f, "Client build error: {}", e),
+            FastmailError::InvalidInput(e) => write!(f, "Invalid input: {}", e),
         }
     }
 }
 
 impl std::error::Error for FastmailError {}
 
-#[derive(Deserialize, Debug)]
+impl FastmailError {
+    /// A stable snake_case name for the variant, for machine-readable output.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            FastmailError::Http(_) => "http",
+            FastmailError::Auth(..) => "auth",
+            FastmailError::Status(..) => "status",
+            FastmailError::Api(_) => "api",
+            FastmailError::Parse(_) => "parse",
+            FastmailError::MissingCapability(_) => "missing_capability",
+            FastmailError::NotFound(_) => "not_found",
+            FastmailError::Build(_) => "build",
+            FastmailError::InvalidInput(_) => "invalid_input",
+        }
+    }
+
+    /// The HTTP status, for errors caused by an unsuccessful response.
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            FastmailError::Auth(status, _) | FastmailError::Status(status, _) => Some(*status),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
 pub struct SessionResponse {
+    #[serde(default)]
+    pub capabilities: HashMap<String, serde_json::Value>,
+    #[serde(default)]
+    pub accounts: HashMap<String, Account>,
     #[serde(rename = "primaryAccounts")]
     pub primary_accounts: HashMap<String, String>,
+    #[serde(default)]
+    pub username: String,
+    #[serde(rename = "apiUrl", default)]
+    pub api_url: String,
+}
+
+impl SessionResponse {
+    pub fn masked_email_account_id(&self) -> Option<&str> {
+        self.primary_accounts
+            .get(MASKED_EMAIL_CAPABILITY)
+            .map(String::as_str)
+    }
+
+    pub fn has_masked_email_capability(&self) -> bool {
+        self.capabilities.contains_key(MASKED_EMAIL_CAPABILITY)
+    }
+
+    /// The maximum number of masked emails, if the server advertises one
+    /// as `maxMaskedEmails` in the account's or the session's capability.
+    pub fn masked_email_limit(&self) -> Option<u64> {
+        let account = self
+            .masked_email_account_id()
+            .and_then(|id| self.accounts.get(id))
+            .and_then(|a| a.account_capabilities.get(MASKED_EMAIL_CAPABILITY));
+        account
+            .into_iter()
+            .chain(self.capabilities.get(MASKED_EMAIL_CAPABILITY))
+            .find_map(|capability| capability.get("maxMaskedEmails")?.as_u64())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Account {
+    #[serde(default)]
+    pub name: String,
+    #[serde(rename = "isPersonal", default)]
+    pub is_personal: bool,
+    #[serde(rename = "isReadOnly", default)]
+    pub is_read_only: bool,
+    #[serde(rename = "accountCapabilities", default)]
+    pub account_capabilities: HashMap<String, serde_json::Value>,
 }
 
 #[derive(Serialize)]
@@ -44,15 +144,116 @@ struct JmapRequest {
     method_calls: Vec<(String, serde_json::Value, String)>,
 }
 
+impl JmapRequest {
+    /// An empty request using only the core capability; each call adds
+    /// the ones its method needs.
+    fn new() -> Self {
+        JmapRequest {
+            using: vec![JMAP_CORE_CAPABILITY.to_string()],
+            method_calls: Vec::new(),
+        }
+    }
+
+    /// Add a method call needing `capabilities` and return its call id,
+    /// e.g. `set-0` for the first call or `get-1` for a second `MaskedEmail/get`.
+    fn call(
+        &mut self,
+        method: &str,
+        capabilities: &[&str],
+        arguments: serde_json::Value,
+    ) -> String {
+        for capability in capabilities {
+            if !self.using.iter().any(|used| used == capability) {
+                self.using.push(capability.to_string());
+            }
+        }
+        let verb = method.rsplit('/').next().unwrap_or(method).to_lowercase();
+        let call_id = format!("{}-{}", verb, self.method_calls.len());
+        self.method_calls.push((method.to_string(), arguments, call_id.clone()));
+        call_id
+    }
+
+    /// Whether the request only reads (`/get`, `/query`, `/changes`), and
+    /// so may be resent after any transport error. Anything with a `/set`
+    /// is a write that the server may already have applied.
+    fn is_idempotent(&self) -> bool {
+        self.method_calls.iter().all(|(method, _, _)| !method.ends_with("/set"))
+    }
+
+    /// The first capability the request uses that `session` doesn't offer.
+    fn missing_capability(&self, session: &SessionResponse) -> Option<&str> {
+        self.using
+            .iter()
+            .find(|urn| !session.capabilities.contains_key(urn.as_str()))
+            .map(String::as_str)
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct JmapResponse {
     #[serde(rename = "methodResponses")]
     pub method_responses: Vec<(String, serde_json::Value, String)>,
 }
 
+impl JmapResponse {
+    /// The arguments of the response to a specific method call, matched by
+    /// both method name and call id so multi-call requests can pick out
+    /// each result regardless of ordering.
+    pub fn response_for(&self, method: &str, call_id: &str) -> Option<&serde_json::Value> {
+        self.method_responses
+            .iter()
+            .find(|(name, _, id)| name == method && id == call_id)
+            .map(|(_, arguments, _)| arguments)
+    }
+}
+
+/// Defines a string newtype for an identifier, so account and masked email
+/// ids can't be passed in each other's place.
+macro_rules! id_type {
+    ($(#[$doc:meta])* $name:ident) => {
+        $(#[$doc])*
+        #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash)]
+        #[serde(transparent)]
+        pub struct $name(String);
+
+        impl From<String> for $name {
+            fn from(id: String) -> Self {
+                Self(id)
+            }
+        }
+
+        impl From<&str> for $name {
+            fn from(id: &str) -> Self {
+                Self(id.to_string())
+            }
+        }
+
+        impl AsRef<str> for $name {
+            fn as_ref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+    };
+}
+
+id_type!(
+    /// A JMAP account id, e.g. `u1234abcd`.
+    AccountId
+);
+id_type!(
+    /// The server-assigned id of a masked email.
+    MaskedEmailId
+);
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct MaskedEmail {
-    pub id: Option<String>,
+    pub id: Option<MaskedEmailId>,
     pub email: String,
     #[serde(default)]
     pub state: Option<String>,
@@ -64,313 +265,3721 @@ pub struct MaskedEmail {
     pub created_at: Option<String>,
     #[serde(rename = "lastMessageAt", default)]
     pub last_message_at: Option<String>,
+    #[serde(default)]
+    pub url: Option<String>,
+    /// The client that created the email, as recorded by the server.
+    #[serde(rename = "createdBy", default)]
+    pub created_by: Option<String>,
 }
 
-pub struct FastmailClient {
-    http: reqwest::blocking::Client,
-    token: String,
-}
-
-impl FastmailClient {
-    pub fn new(token: impl Into<String>) -> Self {
-        Self {
-            http: reqwest::blocking::Client::new(),
-            token: token.into(),
+impl MaskedEmail {
+    /// A one-line `email (domain) — description` rendering, leaving out
+    /// whichever parts are empty.
+    pub fn summary_line(&self) -> String {
+        let mut line = self.email.clone();
+        if let Some(domain) = self.for_domain.as_deref().filter(|d| !d.is_empty()) {
+            line.push_str(&format!(" ({})", domain));
+        }
+        if let Some(description) = self.description.as_deref().filter(|d| !d.is_empty()) {
+            line.push_str(&format!(" — {}", description));
         }
+        line
     }
 
-    pub fn get_session(&self) -> Result<SessionResponse, FastmailError> {
-        let response = self
-            .http
-            .get(FASTMAIL_SESSION_URL)
-            .bearer_auth(&self.token)
-            .send()
-            .map_err(|e| FastmailError::Http(e.to_string()))?;
+    /// The calendar date of `created_at`, or `None` if it's missing or
+    /// doesn't start with a valid `YYYY-MM-DD`.
+    pub fn created_date(&self) -> Option<Date> {
+        date_prefix(self.created_at.as_deref()?)
+    }
 
-        let status = response.status();
-        if !status.is_success() {
-            let body = response.text().unwrap_or_default();
-            return Err(FastmailError::Auth(status.as_u16(), body));
+    /// The calendar date of `last_message_at`, or `None` if the email never
+    /// received mail or the timestamp is invalid.
+    pub fn last_message_date(&self) -> Option<Date> {
+        date_prefix(self.last_message_at.as_deref()?)
+    }
+
+    /// `created_at` parsed, or `None` if it's missing or invalid.
+    pub fn created_timestamp(&self) -> Option<Timestamp> {
+        self.created_at.as_deref()?.parse().ok()
+    }
+
+    /// `last_message_at` parsed, or `None` if it's missing or invalid.
+    pub fn last_message_timestamp(&self) -> Option<Timestamp> {
+        self.last_message_at.as_deref()?.parse().ok()
+    }
+
+    /// When the email was last in use: its last message, or its creation
+    /// if it never received mail.
+    pub fn last_active_date(&self) -> Option<Date> {
+        match self.last_message_at {
+            Some(_) => self.last_message_date(),
+            None => self.created_date(),
         }
+    }
 
-        response
-            .json()
-            .map_err(|e| FastmailError::Parse(e.to_string()))
+    /// The `#tag` tokens in the description; see `description_tags`.
+    pub fn tags(&self) -> Vec<&str> {
+        self.description.as_deref().map(description_tags).unwrap_or_default()
     }
 
-    pub fn get_account_id(&self) -> Result<String, FastmailError> {
-        let session = self.get_session()?;
-        session
-            .primary_accounts
-            .get(MASKED_EMAIL_CAPABILITY)
-            .cloned()
-            .ok_or(FastmailError::MissingCapability)
+    /// Whether the description has `#tag` as a whole token, ignoring case.
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags().iter().any(|t| t.eq_ignore_ascii_case(tag))
     }
 
-    pub fn create_masked_email(
-        &self,
-        account_id: &str,
-        description: Option<&str>,
-        for_domain: Option<&str>,
-    ) -> Result<MaskedEmail, FastmailError> {
-        let request = JmapRequest {
-            using: vec![JMAP_CORE_CAPABILITY.to_string(), MASKED_EMAIL_CAPABILITY.to_string()],
-            method_calls: vec![(
-                "MaskedEmail/set".to_string(),
-                serde_json::json!({
-                    "accountId": account_id,
-                    "create": {
-                        "new": {
-                            "state": "enabled",
-                            "description": description.unwrap_or_default(),
-                            "forDomain": for_domain.unwrap_or_default()
-                        }
-                    }
-                }),
-                "0".to_string(),
-            )],
-        };
+    /// The retry tag a create left at the end of the description because
+    /// removing it failed, e.g. `[tmail#0123456789abcdef]`.
+    pub fn retry_tag(&self) -> Option<&str> {
+        let description = self.description.as_deref()?;
+        let tag = &description[description.rfind('[')?..];
+        let hex = tag.strip_prefix(format!("[{}#", RETRY_TAG).as_str())?.strip_suffix(']')?;
+        (hex.len() == 16 && hex.chars().all(|c| c.is_ascii_hexdigit())).then_some(tag)
+    }
+}
 
-        let response = self
-            .http
-            .post(FASTMAIL_API_URL)
-            .bearer_auth(&self.token)
-            .json(&request)
-            .send()
-            .map_err(|e| FastmailError::Http(e.to_string()))?;
+/// The tags in a description: whitespace-separated tokens starting with
+/// `#`, returned without it. Fastmail has no tags of its own, so they live
+/// in the description.
+pub fn description_tags(description: &str) -> Vec<&str> {
+    description
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix('#'))
+        .filter(|tag| !tag.is_empty())
+        .collect()
+}
 
-        let status = response.status();
-        if !status.is_success() {
-            let body = response.text().unwrap_or_default();
-            return Err(FastmailError::Auth(status.as_u16(), body));
-        }
+/// A description with its `#tag` tokens removed and spacing collapsed.
+pub fn strip_tags(description: &str) -> String {
+    description
+        .split_whitespace()
+        .filter(|word| word.strip_prefix('#').is_none_or(str::is_empty))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
 
-        let jmap: JmapResponse = response
-            .json()
-            .map_err(|e| FastmailError::Parse(e.to_string()))?;
+/// Check a tag given without or with its leading `#`, returning it without.
+pub fn parse_tag(input: &str) -> Result<String, FastmailError> {
+    let tag = input.strip_prefix('#').unwrap_or(input);
+    if tag.is_empty() || tag.contains(|c: char| c.is_whitespace() || c == '#') {
+        return Err(FastmailError::InvalidInput(format!(
+            "'{}' is not a valid tag; use a single word like 'shopping'",
+            input
+        )));
+    }
+    Ok(tag.to_string())
+}
 
-        if let Some((method, result, _)) = jmap.method_responses.first() {
-            if method == "MaskedEmail/set" {
-                if let Some(created) = result.get("created") {
-                    if let Some(new_email) = created.get("new") {
-                        return serde_json::from_value(new_email.clone())
-                            .map_err(|e| FastmailError::Parse(e.to_string()));
-                    }
-                }
-                if let Some(not_created) = result.get("notCreated") {
-                    return Err(FastmailError::Api(format!("{:?}", not_created)));
-                }
-            }
-        }
+fn date_prefix(timestamp: &str) -> Option<Date> {
+    timestamp.get(..10)?.parse().ok()
+}
 
-        Err(FastmailError::Api(format!(
-            "Unexpected response: {:?}",
-            jmap
-        )))
+/// Formats as the summary line followed by the state, e.g.
+/// `abc@fastmail.com (example.com) — newsletter [enabled]`.
+impl std::fmt::Display for MaskedEmail {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.summary_line())?;
+        if let Some(state) = self.state.as_deref().filter(|s| !s.is_empty()) {
+            write!(f, " [{}]", state)?;
+        }
+        Ok(())
     }
+}
 
-    pub fn list_masked_emails(&self, account_id: &str) -> Result<Vec<MaskedEmail>, FastmailError> {
-        let request = JmapRequest {
-            using: vec![JMAP_CORE_CAPABILITY.to_string(), MASKED_EMAIL_CAPABILITY.to_string()],
-            method_calls: vec![(
-                "MaskedEmail/get".to_string(),
-                serde_json::json!({
-                    "accountId": account_id,
-                    "ids": null
-                }),
-                "0".to_string(),
-            )],
-        };
+pub struct FastmailClient {
+    http: reqwest::blocking::Client,
+    token: String,
+    session_url: String,
+    api_url: String,
+    max_retries: u32,
+    retry_delay: Duration,
+    min_interval: Duration,
+    last_request_at: Mutex<Option<Instant>>,
+    last_stats: Mutex<Option<RequestStats>>,
+    jitter: Mutex<Jitter>,
+    session: Mutex<Option<SessionResponse>>,
+    advisories: Mutex<Vec<Advisory>>,
+    on_advisory: Option<AdvisoryHandler>,
+}
 
-        let response = self
-            .http
-            .post(FASTMAIL_API_URL)
-            .bearer_auth(&self.token)
-            .json(&request)
-            .send()
-            .map_err(|e| FastmailError::Http(e.to_string()))?;
+/// An advisory response header, such as a `Deprecation` or `Warning`
+/// notice about upcoming API changes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Advisory {
+    /// Lowercase header name
+    pub header: String,
+    pub value: String,
+}
 
-        let status = response.status();
-        if !status.is_success() {
-            let body = response.text().unwrap_or_default();
-            return Err(FastmailError::Auth(status.as_u16(), body));
-        }
+type AdvisoryHandler = Box<dyn Fn(&Advisory) + Send + Sync>;
 
-        let jmap: JmapResponse = response
-            .json()
-            .map_err(|e| FastmailError::Parse(e.to_string()))?;
+/// Whether a response header carries an advisory worth surfacing.
+fn is_advisory_header(name: &str) -> bool {
+    matches!(name, "warning" | "deprecation" | "sunset")
+        || name.starts_with("x-") && name.contains("deprecat")
+}
 
-        if let Some((method, result, _)) = jmap.method_responses.first() {
-            if method == "MaskedEmail/get" {
-                if let Some(list) = result.get("list") {
-                    return serde_json::from_value(list.clone())
-                        .map_err(|e| FastmailError::Parse(e.to_string()));
-                }
-            }
-        }
+/// xorshift64* generator for retry jitter; statistical quality doesn't
+/// matter here, only that instances don't retry in lockstep.
+struct Jitter(u64);
 
-        Err(FastmailError::Api(format!(
-            "Unexpected response: {:?}",
-            jmap
-        )))
+impl Jitter {
+    fn new(seed: u64) -> Self {
+        // The state must never be zero
+        Jitter(seed | 1)
     }
 
-    pub fn delete_masked_email(&self, account_id: &str, id: &str) -> Result<(), FastmailError> {
-        let request = JmapRequest {
-            using: vec![JMAP_CORE_CAPABILITY.to_string(), MASKED_EMAIL_CAPABILITY.to_string()],
-            method_calls: vec![(
-                "MaskedEmail/set".to_string(),
-                serde_json::json!({
-                    "accountId": account_id,
-                    "update": {
-                        id: {
-                            "state": "disabled"
-                        }
-                    }
-                }),
-                "0".to_string(),
-            )],
-        };
+    fn from_entropy() -> Self {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+        Jitter::new(nanos ^ u64::from(std::process::id()).rotate_left(32))
+    }
 
-        let response = self
-            .http
-            .post(FASTMAIL_API_URL)
-            .bearer_auth(&self.token)
-            .json(&request)
-            .send()
-            .map_err(|e| FastmailError::Http(e.to_string()))?;
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
 
-        let status = response.status();
-        if !status.is_success() {
-            let body = response.text().unwrap_or_default();
-            return Err(FastmailError::Auth(status.as_u16(), body));
-        }
+    /// A uniformly random duration in `[0, max]`.
+    fn up_to(&mut self, max: Duration) -> Duration {
+        let max = max.as_millis() as u64;
+        Duration::from_millis(self.next() % (max + 1))
+    }
+}
 
-        let jmap: JmapResponse = response
-            .json()
-            .map_err(|e| FastmailError::Parse(e.to_string()))?;
+/// Timing and correlation details for a single HTTP round trip.
+#[derive(Debug, Clone)]
+pub struct RequestStats {
+    pub duration: Duration,
+    pub status: u16,
+    /// Server-assigned request id, for correlating with Fastmail support
+    pub request_id: Option<String>,
+}
 
-        if let Some((method, result, _)) = jmap.method_responses.first() {
-            if method == "MaskedEmail/set" {
-                if result.get("updated").and_then(|u| u.get(id)).is_some() {
-                    return Ok(());
-                }
-                if let Some(not_updated) = result.get("notUpdated") {
-                    return Err(FastmailError::Api(format!("{:?}", not_updated)));
-                }
-            }
-        }
+/// A message sent by `send_test_message`, found again by its unique subject.
+#[derive(Debug, Clone)]
+pub struct TestMessage {
+    pub subject: String,
+    /// The mail account it was sent from, where it should also arrive
+    pub account_id: AccountId,
+}
 
-        Err(FastmailError::Api(format!(
-            "Unexpected response: {:?}",
-            jmap
-        )))
+/// One page of `query_masked_emails`, in the server's order.
+#[derive(Debug, Clone)]
+pub struct QueryPage {
+    pub emails: Vec<MaskedEmail>,
+    /// Index of the first entry among all matches
+    pub position: usize,
+    /// How many masked emails match across all pages, if the server counted them
+    pub total: Option<usize>,
+    /// Changes whenever the matches would
+    pub query_state: String,
+}
+
+/// A `MaskedEmail/get` listing and the account state it was taken at.
+#[derive(Debug, Clone)]
+pub struct ListResult {
+    pub emails: Vec<MaskedEmail>,
+    pub state: String,
+}
+
+impl ListResult {
+    pub fn iter(&self) -> std::slice::Iter<'_, MaskedEmail> {
+        self.emails.iter()
     }
 
-    pub fn destroy_masked_email(&self, account_id: &str, id: &str) -> Result<(), FastmailError> {
-        let request = JmapRequest {
-            using: vec![JMAP_CORE_CAPABILITY.to_string(), MASKED_EMAIL_CAPABILITY.to_string()],
-            method_calls: vec![(
-                "MaskedEmail/set".to_string(),
-                serde_json::json!({
-                    "accountId": account_id,
-                    "update": {
-                        id: {
-                            "state": "deleted"
-                        }
-                    }
-                }),
-                "0".to_string(),
-            )],
-        };
+    /// The masked emails that currently receive mail.
+    pub fn enabled(&self) -> impl Iterator<Item = &MaskedEmail> {
+        self.iter().filter(|e| e.state.as_deref() == Some("enabled"))
+    }
 
-        let response = self
-            .http
-            .post(FASTMAIL_API_URL)
-            .bearer_auth(&self.token)
-            .json(&request)
-            .send()
-            .map_err(|e| FastmailError::Http(e.to_string()))?;
+    /// The masked emails for exactly `domain`; see `domain_matches`.
+    pub fn by_domain<'a>(&'a self, domain: &'a str) -> impl Iterator<Item = &'a MaskedEmail> {
+        self.iter().filter(move |e| {
+            e.for_domain
+                .as_deref()
+                .is_some_and(|d| domain_matches(d, domain, false))
+        })
+    }
 
-        let status = response.status();
-        if !status.is_success() {
-            let body = response.text().unwrap_or_default();
-            return Err(FastmailError::Auth(status.as_u16(), body));
+    /// The masked email with this address, ignoring case.
+    pub fn find(&self, email: &str) -> Option<&MaskedEmail> {
+        self.iter().find(|e| e.email.eq_ignore_ascii_case(email))
+    }
+}
+
+/// A listing without a state, e.g. from `MaskedEmailStore::list`, to use
+/// the lookup helpers on.
+impl From<Vec<MaskedEmail>> for ListResult {
+    fn from(emails: Vec<MaskedEmail>) -> Self {
+        ListResult {
+            emails,
+            state: String::new(),
         }
+    }
+}
 
-        let jmap: JmapResponse = response
-            .json()
-            .map_err(|e| FastmailError::Parse(e.to_string()))?;
+impl IntoIterator for ListResult {
+    type Item = MaskedEmail;
+    type IntoIter = std::vec::IntoIter<MaskedEmail>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.emails.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a ListResult {
+    type Item = &'a MaskedEmail;
+    type IntoIter = std::slice::Iter<'a, MaskedEmail>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Lazily pages through an account's masked emails for `iter_masked_emails`.
+struct MaskedEmailPages<'a> {
+    client: &'a FastmailClient,
+    account_id: AccountId,
+    page_size: usize,
+    position: usize,
+    page: VecDeque<MaskedEmail>,
+    /// Set after a short page or an error, so no further requests are made
+    done: bool,
+}
 
-        if let Some((method, result, _)) = jmap.method_responses.first() {
-            if method == "MaskedEmail/set" {
-                if result.get("updated").and_then(|u| u.get(id)).is_some() {
-                    return Ok(());
+impl Iterator for MaskedEmailPages<'_> {
+    type Item = Result<MaskedEmail, FastmailError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.is_empty() && !self.done {
+            match self.client.masked_email_page(&self.account_id, self.position, self.page_size) {
+                Ok(page) => {
+                    self.done = page.len() < self.page_size;
+                    self.position += page.len();
+                    self.page = page.into();
                 }
-                if let Some(not_updated) = result.get("notUpdated") {
-                    return Err(FastmailError::Api(format!("{:?}", not_updated)));
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
                 }
             }
         }
+        self.page.pop_front().map(Ok)
+    }
+}
 
-        Err(FastmailError::Api(format!(
-            "Unexpected response: {:?}",
-            jmap
-        )))
+/// One rejected entry of a batch create, with the spec that caused it.
+#[derive(Debug)]
+pub struct BatchItemError {
+    /// Position of the spec in the batch
+    pub index: usize,
+    /// Boxed to keep `Result`s carrying this error small
+    pub spec: Box<CreateSpec>,
+    /// The server's SetError `type`, e.g. `overQuota`, if it rejected the entry
+    pub code: Option<String>,
+    pub error: FastmailError,
+}
+
+impl BatchItemError {
+    fn new(index: usize, spec: &CreateSpec, error: FastmailError) -> Self {
+        BatchItemError {
+            index,
+            spec: Box::new(spec.clone()),
+            code: None,
+            error,
+        }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl std::fmt::Display for BatchItemError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "entry {} failed: {}", self.index, self.error)
+    }
+}
 
-    fn get_test_token() -> String {
-        std::env::var("FASTMAIL_TOKEN").expect("FASTMAIL_TOKEN env var required for tests")
+impl std::error::Error for BatchItemError {}
+
+/// Properties for a masked email to be created by `create_masked_emails`.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct CreateSpec {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(rename = "forDomain", skip_serializing_if = "Option::is_none")]
+    pub for_domain: Option<String>,
+    /// The exact page the alias was made for, e.g. a signup form
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Start of the generated address, e.g. `amazon` for
+    /// `amazon.x7y2@fastmail.com`; Fastmail appends a random suffix
+    #[serde(rename = "emailPrefix", skip_serializing_if = "Option::is_none")]
+    pub email_prefix: Option<String>,
+    /// Initial state, one of `CREATE_STATES`; `enabled` when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+}
+
+/// Normalize a pasted API token: surrounding whitespace and an
+/// `Authorization`-style `Bearer ` prefix are removed.
+pub fn clean_token(token: &str) -> String {
+    let token = token.trim();
+    match token.split_once(char::is_whitespace) {
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("bearer") => {
+            rest.trim_start().to_string()
+        }
+        _ => token.to_string(),
     }
+}
 
-    #[test]
-    #[ignore] // Run with: cargo test -- --ignored
-    fn test_get_session() {
-        let client = FastmailClient::new(get_test_token());
-        let result = client.get_session();
-        println!("Session result: {:#?}", result);
-        assert!(result.is_ok());
+/// Whether a cleaned token has the shape of a Fastmail API token, e.g.
+/// `fmu1-…`. Only a hint: an odd-looking token may still be accepted.
+pub fn token_looks_valid(token: &str) -> bool {
+    token.starts_with("fm")
+        && token.len() >= 20
+        && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Longest `emailPrefix` Fastmail accepts.
+const MAX_EMAIL_PREFIX_LEN: usize = 64;
+
+/// Check an `emailPrefix` against Fastmail's rules: at most 64 lowercase
+/// letters, digits and underscores.
+pub fn validate_email_prefix(prefix: &str) -> Result<(), FastmailError> {
+    let valid_chars = prefix
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if prefix.is_empty() || prefix.len() > MAX_EMAIL_PREFIX_LEN || !valid_chars {
+        return Err(FastmailError::InvalidInput(format!(
+            "'{}' is not a valid email prefix; use up to {} characters from a-z, 0-9 and _",
+            prefix, MAX_EMAIL_PREFIX_LEN
+        )));
     }
+    Ok(())
+}
 
-    #[test]
-    #[ignore]
-    fn test_get_account_id() {
-        let client = FastmailClient::new(get_test_token());
-        let result = client.get_account_id();
-        println!("Account ID result: {:#?}", result);
-        assert!(result.is_ok());
+/// States a masked email can be created in. `deleted` is only reached by
+/// deleting an existing one.
+pub const CREATE_STATES: [&str; 3] = ["pending", "enabled", "disabled"];
+
+/// Check that `state` is one a new masked email may start in.
+pub fn validate_create_state(state: &str) -> Result<(), FastmailError> {
+    if state == "deleted" {
+        return Err(FastmailError::InvalidInput(
+            "a masked email can't be created as deleted; use pending, enabled or disabled"
+                .to_string(),
+        ));
     }
+    if !CREATE_STATES.contains(&state) {
+        return Err(FastmailError::InvalidInput(format!(
+            "'{}' is not a masked email state; use pending, enabled or disabled",
+            state
+        )));
+    }
+    Ok(())
+}
 
-    #[test]
-    #[ignore]
-    fn test_create_masked_email() {
-        let client = FastmailClient::new(get_test_token());
-        let account_id = client.get_account_id().expect("Failed to get account ID");
-        let result = client.create_masked_email(&account_id, Some("test from tmail"), None);
-        println!("Create masked email result: {:#?}", result);
-        assert!(result.is_ok());
+/// Second-level labels of common country suffixes like `.co.uk`
+const SECOND_LEVEL: [&str; 7] = ["co", "com", "org", "net", "ac", "gov", "edu"];
 
-        // Cleanup
-        let created = result.unwrap();
-        let id = created.id.expect("Created email has no ID");
-        client.destroy_masked_email(&account_id, &id).expect("Failed to cleanup");
+/// The registrable part of a domain, e.g. `amazon.co.uk` for
+/// `www.amazon.co.uk`, lowercased.
+pub fn registrable_domain(domain: &str) -> Option<String> {
+    let domain = domain.trim().trim_end_matches('.').to_lowercase();
+    let labels: Vec<&str> = domain.split('.').collect();
+    let keep = match labels.as_slice() {
+        [""] => return None,
+        [.., _, second, _] if SECOND_LEVEL.contains(second) => 3,
+        _ => 2,
+    };
+    Some(labels[labels.len().saturating_sub(keep)..].join("."))
+}
+
+/// Whether `domain` is `filter`, ignoring case and a trailing dot. With
+/// `subdomains`, any subdomain of `filter` matches too: `example.com`
+/// matches `mail.example.com` but not `notexample.com`.
+pub fn domain_matches(domain: &str, filter: &str, subdomains: bool) -> bool {
+    let clean = |d: &str| d.trim().trim_end_matches('.').to_lowercase();
+    let (domain, filter) = (clean(domain), clean(filter));
+    if filter.is_empty() {
+        return false;
     }
+    domain == filter
+        || subdomains
+            && domain
+                .strip_suffix(filter.as_str())
+                .is_some_and(|rest| rest.ends_with('.'))
+}
 
-    #[test]
+/// Derive an `emailPrefix` from a domain's registrable label, e.g.
+/// `amazon` for `www.amazon.co.uk`.
+pub fn prefix_from_domain(domain: &str) -> Option<String> {
+    let labels: Vec<&str> = domain.trim().trim_end_matches('.').split('.').collect();
+    let label = match labels.as_slice() {
+        [.., name, second, _] if SECOND_LEVEL.contains(second) => name,
+        [.., name, _] => name,
+        [name] => name,
+        [] => return None,
+    };
+    let prefix: String = label
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .take(MAX_EMAIL_PREFIX_LEN)
+        .collect();
+    validate_email_prefix(&prefix).ok().map(|()| prefix)
+}
+
+/// The `MaskedEmail/set` create object for a spec, with its domain
+/// normalized and prefix checked.
+fn create_object(spec: &CreateSpec) -> Result<serde_json::Value, FastmailError> {
+    let mut spec = spec.clone();
+    spec.for_domain = spec
+        .for_domain
+        .as_deref()
+        .map(normalize_domain)
+        .transpose()?
+        .flatten();
+    if let Some(prefix) = &spec.email_prefix {
+        validate_email_prefix(prefix)?;
+    }
+    let state = spec.state.get_or_insert_with(|| "enabled".to_string());
+    validate_create_state(state)?;
+    serde_json::to_value(&spec).map_err(|e| FastmailError::Parse(e.to_string()))
+}
+
+/// Sort newest first by `created_at`, with undated emails last and ties
+/// broken by address, since `MaskedEmail/get` returns them in no set order.
+pub fn sort_newest_first(emails: &mut [MaskedEmail]) {
+    emails.sort_by_cached_key(|e| (Reverse(e.created_timestamp()), e.email.clone()));
+}
+
+/// A calendar date, ordered chronologically.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Date {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+}
+
+impl std::str::FromStr for Date {
+    type Err = FastmailError;
+
+    /// Parse a `YYYY-MM-DD` date, rejecting days that don't exist.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || FastmailError::InvalidInput(format!("'{}' is not a YYYY-MM-DD date", s));
+        let parts: Vec<&str> = s.split('-').collect();
+        let [year, month, day] = parts.as_slice() else {
+            return Err(invalid());
+        };
+        let digits = |part: &str, len: usize| {
+            part.len() == len && part.chars().all(|c| c.is_ascii_digit())
+        };
+        if !digits(year, 4) || !digits(month, 2) || !digits(day, 2) {
+            return Err(invalid());
+        }
+        let date = Date {
+            year: year.parse().map_err(|_| invalid())?,
+            month: month.parse().map_err(|_| invalid())?,
+            day: day.parse().map_err(|_| invalid())?,
+        };
+        let leap = date.year.is_multiple_of(4)
+            && (!date.year.is_multiple_of(100) || date.year.is_multiple_of(400));
+        let days_in_month = match date.month {
+            1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+            4 | 6 | 9 | 11 => 30,
+            2 if leap => 29,
+            2 => 28,
+            _ => return Err(invalid()),
+        };
+        if date.day == 0 || date.day > days_in_month {
+            return Err(invalid());
+        }
+        Ok(date)
+    }
+}
+
+impl Date {
+    /// Today's date in UTC.
+    pub fn today() -> Date {
+        let secs = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Date::from_days(secs as i64 / 86400)
+    }
+
+    /// The date `days` days earlier.
+    pub fn days_before(self, days: u32) -> Date {
+        Date::from_days(self.to_days() - i64::from(days))
+    }
+
+    /// Days since 1970-01-01, using Howard Hinnant's civil calendar algorithm.
+    fn to_days(self) -> i64 {
+        let (month, day) = (i64::from(self.month), i64::from(self.day));
+        let year = i64::from(self.year) - i64::from(month <= 2);
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146_097 + day_of_era - 719_468
+    }
+
+    fn from_days(days: i64) -> Date {
+        let days = days + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+        Date {
+            year: year.clamp(0, 9999) as u16,
+            month: month as u8,
+            day: day as u8,
+        }
+    }
+}
+
+impl std::fmt::Display for Date {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+/// A JMAP `UTCDate` such as `2024-01-15T10:30:00.5Z`, ordered
+/// chronologically down to fractions of a second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    pub date: Date,
+    /// Nanoseconds since midnight
+    pub nanos: u64,
+}
+
+impl std::str::FromStr for Timestamp {
+    type Err = FastmailError;
+
+    /// Parse `YYYY-MM-DDTHH:MM:SS` with optional fractional seconds and a
+    /// trailing `Z`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || FastmailError::InvalidInput(format!("'{}' is not a UTC timestamp", s));
+        let (date, time) = s.split_once('T').ok_or_else(invalid)?;
+        let time = time.strip_suffix('Z').ok_or_else(invalid)?;
+        let digits = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit());
+        let (time, fraction) = match time.split_once('.') {
+            Some((time, fraction)) if digits(fraction) => (time, fraction),
+            Some(_) => return Err(invalid()),
+            None => (time, ""),
+        };
+        let parts: Vec<&str> = time.split(':').collect();
+        let [hour, minute, second] = parts.as_slice() else {
+            return Err(invalid());
+        };
+        let mut seconds = 0;
+        // A leap second may read :60
+        for (part, limit) in [(hour, 24), (minute, 60), (second, 61)] {
+            let value: u64 = part.parse().map_err(|_| invalid())?;
+            if part.len() != 2 || !digits(part) || value >= limit {
+                return Err(invalid());
+            }
+            seconds = seconds * 60 + value;
+        }
+        // Pad or cut the fraction to nine digits of nanoseconds
+        let fraction: String = fraction.chars().chain(std::iter::repeat('0')).take(9).collect();
+        Ok(Timestamp {
+            date: date.parse().map_err(|_| invalid())?,
+            nanos: seconds * 1_000_000_000 + fraction.parse::<u64>().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// The lowercase host of a full URL like `https://Example.com/signup`,
+/// or `None` if the input has no scheme.
+pub fn domain_from_url(url: &str) -> Option<String> {
+    let (_, rest) = url.trim().split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit('@').next()?;
+    let host = host.split(':').next()?;
+    if host.is_empty() {
+        return None;
+    }
+    Some(host.to_lowercase())
+}
+
+/// Normalize a `forDomain` value to a trimmed, lowercase bare domain.
+///
+/// Empty input yields `None`. Values with a scheme or path are rejected
+/// rather than guessed at, with the bare domain suggested in the error.
+pub fn normalize_domain(domain: &str) -> Result<Option<String>, FastmailError> {
+    let domain = domain.trim().to_lowercase();
+    if domain.is_empty() {
+        return Ok(None);
+    }
+    if domain.contains("://")
+        || domain.contains(['/', '?', '#'])
+        || domain.contains(char::is_whitespace)
+    {
+        let host = domain
+            .split_once("://")
+            .map_or(domain.as_str(), |(_, rest)| rest)
+            .split(['/', '?', '#'])
+            .next()
+            .unwrap_or_default();
+        return Err(FastmailError::InvalidInput(format!(
+            "'{}' is not a bare domain; use just the host, e.g. '{}'",
+            domain, host
+        )));
+    }
+    Ok(Some(domain))
+}
+
+/// Describe a JMAP method-level or request-level error, e.g.
+/// `unknownCapability: The request used an unsupported capability`.
+fn jmap_error_message(error: &serde_json::Value) -> String {
+    let kind = error
+        .get("type")
+        .and_then(|t| t.as_str())
+        .unwrap_or("unknown");
+    let kind = kind.strip_prefix("urn:ietf:params:jmap:error:").unwrap_or(kind);
+    let detail = error
+        .get("description")
+        .or_else(|| error.get("detail"))
+        .and_then(|d| d.as_str());
+    match detail {
+        Some(detail) => format!("{}: {}", kind, detail),
+        None => kind.to_string(),
+    }
+}
+
+/// How an update changes one field of a masked email.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum UpdateSpec {
+    /// Leave the field unchanged (omitted from the patch).
+    #[default]
+    Keep,
+    /// Set the field to a new value.
+    Set(String),
+    /// Remove the field's value (sent as `null`).
+    Clear,
+}
+
+impl UpdateSpec {
+    fn apply(self, patch: &mut serde_json::Map<String, serde_json::Value>, key: &str) {
+        match self {
+            UpdateSpec::Keep => {}
+            UpdateSpec::Set(value) => {
+                patch.insert(key.to_string(), serde_json::Value::String(value));
+            }
+            UpdateSpec::Clear => {
+                patch.insert(key.to_string(), serde_json::Value::Null);
+            }
+        }
+    }
+}
+
+/// The `MaskedEmail/set` patch for an update. A domain that normalizes to
+/// nothing clears the field.
+fn update_patch(
+    description: UpdateSpec,
+    for_domain: UpdateSpec,
+    url: UpdateSpec,
+) -> Result<serde_json::Value, FastmailError> {
+    let for_domain = match for_domain {
+        UpdateSpec::Set(domain) => match normalize_domain(&domain)? {
+            Some(domain) => UpdateSpec::Set(domain),
+            None => UpdateSpec::Clear,
+        },
+        other => other,
+    };
+    let mut patch = serde_json::Map::new();
+    description.apply(&mut patch, "description");
+    for_domain.apply(&mut patch, "forDomain");
+    url.apply(&mut patch, "url");
+    Ok(serde_json::Value::Object(patch))
+}
+
+/// Build the created `MaskedEmail` from the properties sent and the
+/// server-set ones (id, email, createdAt) returned in `created`.
+fn created_email(
+    sent: &serde_json::Value,
+    created: &serde_json::Value,
+) -> Result<MaskedEmail, FastmailError> {
+    let mut email = sent.clone();
+    if let (Some(email), Some(created)) = (email.as_object_mut(), created.as_object()) {
+        for (key, value) in created {
+            email.insert(key.clone(), value.clone());
+        }
+    }
+    parse_masked_email(email)
+}
+
+/// Longest snippet of an offending entry quoted in a parse error.
+const PARSE_SNIPPET_LEN: usize = 200;
+
+/// Deserialize one masked email, quoting the raw entry on failure so a
+/// report shows what the server actually sent.
+fn parse_masked_email(value: serde_json::Value) -> Result<MaskedEmail, FastmailError> {
+    serde_json::from_value(value.clone()).map_err(|e| {
+        let mut snippet = value.to_string();
+        if snippet.len() > PARSE_SNIPPET_LEN {
+            let mut end = PARSE_SNIPPET_LEN;
+            while !snippet.is_char_boundary(end) {
+                end -= 1;
+            }
+            snippet.truncate(end);
+            snippet.push('…');
+        }
+        FastmailError::Parse(format!("{} in masked email {}", e, snippet))
+    })
+}
+
+/// Deserialize a `list` of masked emails entry by entry; see
+/// `parse_masked_email`.
+fn parse_masked_emails(list: serde_json::Value) -> Result<Vec<MaskedEmail>, FastmailError> {
+    match list {
+        serde_json::Value::Array(entries) => entries.into_iter().map(parse_masked_email).collect(),
+        _ => Err(FastmailError::Parse("expected a list of masked emails".to_string())),
+    }
+}
+
+fn required_state(new_state: Option<String>) -> Result<String, FastmailError> {
+    new_state.ok_or_else(|| {
+        FastmailError::Parse("MaskedEmail/set response has no newState".to_string())
+    })
+}
+
+fn set_error_message(error: &serde_json::Value) -> String {
+    let kind = error.get("type").and_then(|t| t.as_str()).unwrap_or("unknown");
+    match error.get("description").and_then(|d| d.as_str()) {
+        Some(description) => format!("{}: {}", kind, description),
+        None => kind.to_string(),
+    }
+}
+
+pub struct FastmailClientBuilder {
+    token: String,
+    proxy: Option<String>,
+    session_url: String,
+    api_url: String,
+    max_retries: u32,
+    retry_delay: Duration,
+    min_interval: Duration,
+    jitter_seed: Option<u64>,
+    http_client: Option<reqwest::blocking::Client>,
+    pool_idle_timeout: Option<Duration>,
+    pool_max_idle_per_host: Option<usize>,
+    timeout: Option<Duration>,
+    compression: bool,
+    user_agent: Option<String>,
+    root_certificates: Vec<Vec<u8>>,
+    on_advisory: Option<AdvisoryHandler>,
+}
+
+impl FastmailClientBuilder {
+    /// Route all requests through an HTTP(S) or SOCKS proxy URL.
+    pub fn proxy(mut self, url: impl Into<String>) -> Self {
+        self.proxy = Some(url.into());
+        self
+    }
+
+    pub fn session_url(mut self, url: impl Into<String>) -> Self {
+        self.session_url = url.into();
+        self
+    }
+
+    pub fn api_url(mut self, url: impl Into<String>) -> Self {
+        self.api_url = url.into();
+        self
+    }
+
+    /// How many times a transient failure (connection error, timeout,
+    /// 429 or 5xx) is retried before giving up. Zero disables retries.
+    /// A create is only resent after checking it didn't already happen.
+    pub fn max_retries(mut self, retries: u32) -> Self {
+        self.max_retries = retries;
+        self
+    }
+
+    /// Base delay for exponential backoff between retries. Each wait is a
+    /// random fraction of `delay * 2^attempt`, capped at 30 seconds.
+    pub fn retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Seed the retry jitter so backoff delays are reproducible, e.g. in tests.
+    pub fn jitter_seed(mut self, seed: u64) -> Self {
+        self.jitter_seed = Some(seed);
+        self
+    }
+
+    /// Minimum time between outgoing requests, to stay under Fastmail's
+    /// rate limits in bulk loops. Off by default.
+    pub fn min_interval(mut self, interval: Duration) -> Self {
+        self.min_interval = interval;
+        self
+    }
+
+    /// Share an existing HTTP client, and its connection pool, instead of
+    /// creating a new one. `proxy`, the pool and TLS settings and the user
+    /// agent are ignored since they belong to the supplied client.
+    pub fn with_http_client(mut self, client: reqwest::blocking::Client) -> Self {
+        self.http_client = Some(client);
+        self
+    }
+
+    /// Give up on a request that hasn't completed within `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Ask for gzip-compressed responses and decompress them (the default),
+    /// which shrinks large listings considerably. Request bodies are sent
+    /// uncompressed, since JMAP servers aren't required to accept gzip.
+    pub fn compression(mut self, enabled: bool) -> Self {
+        self.compression = enabled;
+        self
+    }
+
+    /// Send `agent` as the User-Agent header.
+    pub fn user_agent(mut self, agent: impl Into<String>) -> Self {
+        self.user_agent = Some(agent.into());
+        self
+    }
+
+    /// Also trust the PEM-encoded CA certificate `pem`, e.g. for a proxy
+    /// that inspects TLS traffic.
+    pub fn add_root_certificate(mut self, pem: impl Into<Vec<u8>>) -> Self {
+        self.root_certificates.push(pem.into());
+        self
+    }
+
+    /// Call `handler` the first time each advisory header (e.g. a
+    /// deprecation notice) is seen in a response.
+    pub fn on_advisory(mut self, handler: impl Fn(&Advisory) + Send + Sync + 'static) -> Self {
+        self.on_advisory = Some(Box::new(handler));
+        self
+    }
+
+    /// How long an idle pooled connection is kept open for reuse.
+    pub fn pool_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.pool_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Maximum number of idle connections kept per host.
+    pub fn pool_max_idle_per_host(mut self, max: usize) -> Self {
+        self.pool_max_idle_per_host = Some(max);
+        self
+    }
+
+    pub fn build(self) -> Result<FastmailClient, FastmailError> {
+        let http = match self.http_client {
+            Some(client) => client,
+            None => {
+                let mut http = reqwest::blocking::Client::builder();
+                #[cfg(feature = "tls-rustls")]
+                {
+                    http = http.use_rustls_tls();
+                }
+                #[cfg(feature = "tls-native")]
+                {
+                    http = http.use_native_tls();
+                }
+                if let Some(url) = &self.proxy {
+                    let proxy = reqwest::Proxy::all(url).map_err(|e| {
+                        FastmailError::Build(format!("invalid proxy URL '{}': {}", url, e))
+                    })?;
+                    http = http.proxy(proxy);
+                }
+                if let Some(timeout) = self.pool_idle_timeout {
+                    http = http.pool_idle_timeout(timeout);
+                }
+                if let Some(max) = self.pool_max_idle_per_host {
+                    http = http.pool_max_idle_per_host(max);
+                }
+                if let Some(timeout) = self.timeout {
+                    http = http.timeout(timeout);
+                }
+                http = http.gzip(self.compression);
+                if let Some(agent) = &self.user_agent {
+                    http = http.user_agent(agent);
+                }
+                for pem in &self.root_certificates {
+                    let cert = reqwest::Certificate::from_pem(pem).map_err(|e| {
+                        FastmailError::Build(format!("invalid CA certificate: {}", e))
+                    })?;
+                    http = http.add_root_certificate(cert);
+                }
+                http.build()
+                    .map_err(|e| FastmailError::Build(e.to_string()))?
+            }
+        };
+
+        Ok(FastmailClient {
+            http,
+            token: self.token,
+            session_url: self.session_url,
+            api_url: self.api_url,
+            max_retries: self.max_retries,
+            retry_delay: self.retry_delay,
+            min_interval: self.min_interval,
+            last_request_at: Mutex::new(None),
+            last_stats: Mutex::new(None),
+            jitter: Mutex::new(self.jitter_seed.map_or_else(Jitter::from_entropy, Jitter::new)),
+            session: Mutex::new(None),
+            advisories: Mutex::new(Vec::new()),
+            on_advisory: self.on_advisory,
+        })
+    }
+}
+
+/// `Auth` for a rejected token, `Status` for any other unsuccessful response.
+fn status_error(status: reqwest::StatusCode, body: String) -> FastmailError {
+    match status {
+        reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN => {
+            FastmailError::Auth(status.as_u16(), body)
+        }
+        _ => FastmailError::Status(status.as_u16(), body),
+    }
+}
+
+fn is_retryable_status(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+fn is_retryable_error(error: &reqwest::Error) -> bool {
+    error.is_connect() || error.is_timeout()
+}
+
+impl FastmailClient {
+    /// Create a client with its own connection pool. When issuing many
+    /// operations, reuse one client (or share an HTTP client through
+    /// `builder().with_http_client()`) so connections are kept alive.
+    pub fn new(token: impl Into<String>) -> Self {
+        Self {
+            http: reqwest::blocking::Client::new(),
+            token: clean_token(&token.into()),
+            session_url: FASTMAIL_SESSION_URL.to_string(),
+            api_url: FASTMAIL_API_URL.to_string(),
+            max_retries: DEFAULT_MAX_RETRIES,
+            retry_delay: DEFAULT_RETRY_DELAY,
+            min_interval: Duration::ZERO,
+            last_request_at: Mutex::new(None),
+            last_stats: Mutex::new(None),
+            jitter: Mutex::new(Jitter::from_entropy()),
+            session: Mutex::new(None),
+            advisories: Mutex::new(Vec::new()),
+            on_advisory: None,
+        }
+    }
+
+    pub fn builder(token: impl Into<String>) -> FastmailClientBuilder {
+        FastmailClientBuilder {
+            token: clean_token(&token.into()),
+            proxy: None,
+            session_url: FASTMAIL_SESSION_URL.to_string(),
+            api_url: FASTMAIL_API_URL.to_string(),
+            max_retries: DEFAULT_MAX_RETRIES,
+            retry_delay: DEFAULT_RETRY_DELAY,
+            min_interval: Duration::ZERO,
+            jitter_seed: None,
+            http_client: None,
+            pool_idle_timeout: None,
+            pool_max_idle_per_host: None,
+            timeout: None,
+            compression: true,
+            user_agent: None,
+            root_certificates: Vec::new(),
+            on_advisory: None,
+        }
+    }
+
+    /// Send a request, retrying transient failures with jittered exponential
+    /// backoff.
+    fn execute(
+        &self,
+        request: impl Fn() -> reqwest::blocking::RequestBuilder,
+    ) -> Result<reqwest::blocking::Response, FastmailError> {
+        self.execute_with(request, true)
+    }
+
+    /// Send with retries. Requests that aren't `idempotent` are only
+    /// resent on 429/5xx, since a transport error may hide a request the
+    /// server already applied.
+    fn execute_with(
+        &self,
+        request: impl Fn() -> reqwest::blocking::RequestBuilder,
+        idempotent: bool,
+    ) -> Result<reqwest::blocking::Response, FastmailError> {
+        let mut attempt = 0;
+        loop {
+            self.throttle();
+            let started = Instant::now();
+            let result = request().bearer_auth(&self.token).send();
+            if let Ok(response) = &result {
+                self.record_stats(started.elapsed(), response);
+            }
+            match result {
+                Ok(response)
+                    if is_retryable_status(response.status()) && attempt < self.max_retries => {}
+                Ok(response) => return Ok(response),
+                Err(e)
+                    if idempotent && is_retryable_error(&e) && attempt < self.max_retries => {}
+                Err(e) => return Err(FastmailError::Http(e.to_string())),
+            }
+            std::thread::sleep(self.backoff(attempt));
+            attempt += 1;
+        }
+    }
+
+    /// Full jitter: a random delay up to `retry_delay * 2^attempt`, capped,
+    /// so many clients hitting the same rate limit don't retry together.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .retry_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(MAX_RETRY_DELAY);
+        match self.jitter.lock() {
+            Ok(mut jitter) => jitter.up_to(ceiling),
+            Err(_) => ceiling,
+        }
+    }
+
+    /// A random value for tagging requests, from the jitter generator.
+    fn random(&self) -> u64 {
+        match self.jitter.lock() {
+            Ok(mut jitter) => jitter.next(),
+            Err(_) => Jitter::from_entropy().next(),
+        }
+    }
+
+    /// Sleep until `min_interval` has passed since the previous request.
+    fn throttle(&self) {
+        if self.min_interval.is_zero() {
+            return;
+        }
+        let Ok(mut last) = self.last_request_at.lock() else {
+            return;
+        };
+        if let Some(wait) = last.and_then(|at| self.min_interval.checked_sub(at.elapsed())) {
+            std::thread::sleep(wait);
+        }
+        *last = Some(Instant::now());
+    }
+
+    fn record_stats(&self, duration: Duration, response: &reqwest::blocking::Response) {
+        let request_id = response
+            .headers()
+            .iter()
+            .find(|(name, _)| name.as_str().ends_with("request-id"))
+            .and_then(|(_, value)| value.to_str().ok())
+            .map(str::to_string);
+        let stats = RequestStats {
+            duration,
+            status: response.status().as_u16(),
+            request_id,
+        };
+        if let Ok(mut last) = self.last_stats.lock() {
+            *last = Some(stats);
+        }
+        self.record_advisories(response);
+    }
+
+    fn record_advisories(&self, response: &reqwest::blocking::Response) {
+        let Ok(mut seen) = self.advisories.lock() else {
+            return;
+        };
+        for (name, value) in response.headers() {
+            if !is_advisory_header(name.as_str()) {
+                continue;
+            }
+            let advisory = Advisory {
+                header: name.as_str().to_string(),
+                value: String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            };
+            if seen.contains(&advisory) {
+                continue;
+            }
+            if let Some(handler) = &self.on_advisory {
+                handler(&advisory);
+            }
+            seen.push(advisory);
+        }
+    }
+
+    /// Each distinct advisory header (deprecation notices, warnings) this
+    /// client has received, in the order first seen.
+    pub fn advisories(&self) -> Vec<Advisory> {
+        self.advisories.lock().map(|seen| seen.clone()).unwrap_or_default()
+    }
+
+    /// Stats for the most recent HTTP response received by this client.
+    pub fn last_request_stats(&self) -> Option<RequestStats> {
+        self.last_stats.lock().ok().and_then(|last| last.clone())
+    }
+
+    /// The session, fetched on first use and then reused for the life of
+    /// the client.
+    pub fn get_session(&self) -> Result<SessionResponse, FastmailError> {
+        if let Some(session) = self.session.lock().ok().and_then(|s| s.clone()) {
+            return Ok(session);
+        }
+        self.refresh_session()
+    }
+
+    /// Fetch the session again, replacing the one `get_session` reuses.
+    pub fn refresh_session(&self) -> Result<SessionResponse, FastmailError> {
+        let response = self.execute(|| self.http.get(&self.session_url))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().unwrap_or_default();
+            return Err(status_error(status, body));
+        }
+
+        let session: SessionResponse = response
+            .json()
+            .map_err(|e| FastmailError::Parse(e.to_string()))?;
+        if let Ok(mut cached) = self.session.lock() {
+            *cached = Some(session.clone());
+        }
+        Ok(session)
+    }
+
+    pub fn get_account_id(&self) -> Result<AccountId, FastmailError> {
+        let session = self.get_session()?;
+        session
+            .masked_email_account_id()
+            .map(AccountId::from)
+            .ok_or_else(|| {
+                FastmailError::MissingCapability(MASKED_EMAIL_CAPABILITY.to_string())
+            })
+    }
+
+    /// Server capabilities from the session, e.g. to read `maxObjectsInSet`
+    /// from the core capability before issuing batch operations.
+    pub fn capabilities(&self) -> Result<HashMap<String, serde_json::Value>, FastmailError> {
+        Ok(self.get_session()?.capabilities)
+    }
+
+    /// Send a JMAP request. Reads are retried on any transient failure;
+    /// writes only on 429/5xx, and creates rely on a tagged description to
+    /// recover from the rest.
+    ///
+    /// Capabilities the session is known not to offer fail before sending.
+    fn send(&self, request: &JmapRequest) -> Result<JmapResponse, FastmailError> {
+        if let Ok(session) = self.session.lock() {
+            if let Some(urn) = session.as_ref().and_then(|s| request.missing_capability(s)) {
+                return Err(FastmailError::MissingCapability(urn.to_string()));
+            }
+        }
+        let idempotent = request.is_idempotent();
+        let response =
+            self.execute_with(|| self.http.post(&self.api_url).json(request), idempotent)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().unwrap_or_default();
+            // Malformed requests are rejected with an RFC 7807 problem document
+            if status == reqwest::StatusCode::BAD_REQUEST {
+                if let Ok(problem) = serde_json::from_str::<serde_json::Value>(&body) {
+                    // Name the capability, which the problem document doesn't
+                    let unknown = "urn:ietf:params:jmap:error:unknownCapability";
+                    if problem.get("type").and_then(|t| t.as_str()) == Some(unknown) {
+                        let missing = self
+                            .get_session()
+                            .ok()
+                            .and_then(|s| request.missing_capability(&s).map(str::to_string));
+                        if let Some(urn) = missing {
+                            return Err(FastmailError::MissingCapability(urn));
+                        }
+                    }
+                    if problem.get("type").is_some() {
+                        return Err(FastmailError::Api(jmap_error_message(&problem)));
+                    }
+                }
+            }
+            return Err(status_error(status, body));
+        }
+
+        let jmap: JmapResponse = response
+            .json()
+            .map_err(|e| FastmailError::Parse(e.to_string()))?;
+
+        // Responses are matched to calls by id, so one the request didn't
+        // make means they can't be trusted to line up
+        let unexpected = jmap.method_responses.iter().find(|(_, _, id)| {
+            !request.method_calls.iter().any(|(_, _, call_id)| call_id == id)
+        });
+        if let Some((name, _, id)) = unexpected {
+            return Err(FastmailError::Parse(format!(
+                "{} response has unexpected call id '{}'",
+                name, id
+            )));
+        }
+
+        let error = jmap.method_responses.iter().find(|(name, _, _)| name == "error");
+        if let Some((_, error, _)) = error {
+            return Err(FastmailError::Api(jmap_error_message(error)));
+        }
+        Ok(jmap)
+    }
+
+    pub fn create_masked_email(
+        &self,
+        account_id: &AccountId,
+        description: Option<&str>,
+        for_domain: Option<&str>,
+    ) -> Result<MaskedEmail, FastmailError> {
+        let spec = CreateSpec {
+            description: Some(description.unwrap_or_default().to_string()),
+            for_domain: Some(for_domain.unwrap_or_default().to_string()),
+            ..Default::default()
+        };
+        self.create_masked_email_with(account_id, &spec)
+    }
+
+    /// Create a masked email from a full `CreateSpec`, for properties
+    /// beyond description and domain.
+    ///
+    /// With retries enabled, the description is sent with a unique tag, so
+    /// when the request fails in transit an alias the server created anyway
+    /// is found and returned instead of being created twice. The tag is
+    /// removed again once the alias exists; if that fails, the returned
+    /// email's `retry_tag` says so.
+    pub fn create_masked_email_with(
+        &self,
+        account_id: &AccountId,
+        spec: &CreateSpec,
+    ) -> Result<MaskedEmail, FastmailError> {
+        let mut new = create_object(spec)?;
+        // Without retries nothing needs finding, so the create stays a single
+        // write. `createdBy` is set by the server, so only the description
+        // can carry the tag.
+        let marked = (self.max_retries > 0).then(|| {
+            let tag = format!("[{}#{:016x}]", RETRY_TAG, self.random());
+            match spec.description.as_deref().filter(|d| !d.is_empty()) {
+                Some(description) => format!("{} {}", description, tag),
+                None => tag,
+            }
+        });
+        if let Some(marked) = &marked {
+            new["description"] = serde_json::json!(marked);
+        }
+
+        let mut request = JmapRequest::new();
+        let call_id = request.call(
+            "MaskedEmail/set",
+            &[MASKED_EMAIL_CAPABILITY],
+            serde_json::json!({
+                "accountId": account_id,
+                "create": {
+                    "new": &new
+                }
+            }),
+        );
+
+        let mut attempt = 0;
+        let jmap = loop {
+            match self.send(&request) {
+                Err(FastmailError::Http(_)) if attempt < self.max_retries => {
+                    // Look for the alias before resending, retrying the lookup too
+                    let existing = loop {
+                        std::thread::sleep(self.backoff(attempt));
+                        attempt += 1;
+                        match self.list_masked_emails(account_id) {
+                            Ok(emails) => {
+                                break emails
+                                    .into_iter()
+                                    .find(|e| marked.is_some() && e.description == marked)
+                            }
+                            Err(FastmailError::Http(_)) if attempt < self.max_retries => {}
+                            Err(e) => return Err(e),
+                        }
+                    };
+                    if let Some(existing) = existing {
+                        return Ok(self.untag(account_id, existing, &spec.description));
+                    }
+                }
+                result => break result?,
+            }
+        };
+
+        if let Some(result) = jmap.response_for("MaskedEmail/set", &call_id) {
+            if let Some(created) = result.get("created") {
+                if let Some(new_email) = created.get("new") {
+                    let email = created_email(&new, new_email)?;
+                    return Ok(match marked {
+                        Some(_) => self.untag(account_id, email, &spec.description),
+                        None => email,
+                    });
+                }
+            }
+            if let Some(error) = result.get("notCreated").and_then(|n| n.get("new")) {
+                return Err(FastmailError::Api(set_error_message(error)));
+            }
+        }
+
+        Err(FastmailError::Api(format!(
+            "Unexpected response: {:?}",
+            jmap
+        )))
+    }
+
+    /// Put back the description `create_masked_email_with` tagged. The
+    /// alias exists either way, so a failure doesn't fail the create; the
+    /// tag stays in the returned description for `retry_tag` to report.
+    fn untag(
+        &self,
+        account_id: &AccountId,
+        mut email: MaskedEmail,
+        description: &Option<String>,
+    ) -> MaskedEmail {
+        let Some(id) = &email.id else {
+            return email;
+        };
+        let patch = serde_json::json!({"description": description.as_deref().unwrap_or("")});
+        if self.update(account_id, id, patch).is_ok() {
+            email.description = description.clone();
+        }
+        email
+    }
+
+    /// Create several masked emails in a single `MaskedEmail/set` call.
+    ///
+    /// Returns one result per spec, in input order, so a single rejected
+    /// entry doesn't fail the whole batch.
+    pub fn create_masked_emails(
+        &self,
+        account_id: &AccountId,
+        specs: &[CreateSpec],
+    ) -> Result<Vec<Result<MaskedEmail, BatchItemError>>, FastmailError> {
+        // Entries with an invalid domain or prefix fail individually without being sent
+        let mut invalid: HashMap<usize, FastmailError> = HashMap::new();
+        let mut create = serde_json::Map::new();
+        for (i, spec) in specs.iter().enumerate() {
+            match create_object(spec) {
+                Ok(object) => {
+                    create.insert(format!("c{}", i), object);
+                }
+                Err(e) => {
+                    invalid.insert(i, e);
+                }
+            }
+        }
+        if create.is_empty() {
+            return Ok(specs
+                .iter()
+                .enumerate()
+                .map(|(i, spec)| Err(BatchItemError::new(i, spec, invalid.remove(&i).unwrap())))
+                .collect());
+        }
+
+        let mut request = JmapRequest::new();
+        let call_id = request.call(
+            "MaskedEmail/set",
+            &[MASKED_EMAIL_CAPABILITY],
+            serde_json::json!({
+                "accountId": account_id,
+                "create": &create
+            }),
+        );
+
+        // Not resent after a transport error, which could duplicate the batch
+        let jmap = self.send(&request)?;
+
+        if let Some(result) = jmap.response_for("MaskedEmail/set", &call_id) {
+            let results = specs
+                .iter()
+                .enumerate()
+                .map(|(i, spec)| {
+                    if let Some(e) = invalid.remove(&i) {
+                        return Err(BatchItemError::new(i, spec, e));
+                    }
+                    let key = format!("c{}", i);
+                    if let Some(created) = result.get("created").and_then(|c| c.get(&key)) {
+                        created_email(&create[&key], created)
+                            .map_err(|e| BatchItemError::new(i, spec, e))
+                    } else if let Some(error) =
+                        result.get("notCreated").and_then(|c| c.get(&key))
+                    {
+                        let error_type = error.get("type").and_then(|t| t.as_str());
+                        Err(BatchItemError {
+                            code: error_type.map(str::to_string),
+                            ..BatchItemError::new(
+                                i,
+                                spec,
+                                FastmailError::Api(set_error_message(error)),
+                            )
+                        })
+                    } else {
+                        let error = FastmailError::Api(format!("No result for {}", key));
+                        Err(BatchItemError::new(i, spec, error))
+                    }
+                })
+                .collect();
+            return Ok(results);
+        }
+
+        Err(FastmailError::Api(format!(
+            "Unexpected response: {:?}",
+            jmap
+        )))
+    }
+
+    pub fn list_masked_emails(
+        &self,
+        account_id: &AccountId,
+    ) -> Result<Vec<MaskedEmail>, FastmailError> {
+        Ok(self.list_masked_emails_with_state(account_id)?.emails)
+    }
+
+    /// List masked emails along with the `state` they reflect, the baseline
+    /// for fetching later changes.
+    pub fn list_masked_emails_with_state(
+        &self,
+        account_id: &AccountId,
+    ) -> Result<ListResult, FastmailError> {
+        let mut result = self.masked_email_get(account_id, None)?;
+        let emails = parse_masked_emails(result["list"].take())?;
+        let state = result.get("state").and_then(|s| s.as_str()).ok_or_else(|| {
+            FastmailError::Parse("MaskedEmail/get response has no state".to_string())
+        })?;
+        Ok(ListResult {
+            emails,
+            state: state.to_string(),
+        })
+    }
+
+    /// The `list` array of masked emails exactly as the server sent it, for
+    /// fields this crate doesn't model yet or custom parsing.
+    pub fn list_masked_emails_raw(
+        &self,
+        account_id: &AccountId,
+    ) -> Result<serde_json::Value, FastmailError> {
+        Ok(self.masked_email_get(account_id, None)?["list"].take())
+    }
+
+    /// Fetch one masked email by id, e.g. to check on one just created.
+    pub fn get_masked_email(
+        &self,
+        account_id: &AccountId,
+        id: &MaskedEmailId,
+    ) -> Result<MaskedEmail, FastmailError> {
+        let mut result = self.masked_email_get(account_id, Some(std::slice::from_ref(id)))?;
+        let emails = parse_masked_emails(result["list"].take())?;
+        emails
+            .into_iter()
+            .next()
+            .ok_or_else(|| FastmailError::NotFound(format!("masked email with id '{}'", id)))
+    }
+
+    /// The arguments of a `MaskedEmail/get` response for `ids`, or every
+    /// masked email with `None`. Its `list` is present but unparsed.
+    fn masked_email_get(
+        &self,
+        account_id: &AccountId,
+        ids: Option<&[MaskedEmailId]>,
+    ) -> Result<serde_json::Value, FastmailError> {
+        let mut request = JmapRequest::new();
+        let call_id = request.call(
+            "MaskedEmail/get",
+            &[MASKED_EMAIL_CAPABILITY],
+            serde_json::json!({
+                "accountId": account_id,
+                "ids": ids
+            }),
+        );
+
+        let jmap = self.send(&request)?;
+
+        if let Some(result) = jmap.response_for("MaskedEmail/get", &call_id) {
+            if result.get("list").is_some_and(|list| list.is_array()) {
+                return Ok(result.clone());
+            }
+        }
+
+        Err(FastmailError::Api(format!(
+            "Unexpected response: {:?}",
+            jmap
+        )))
+    }
+
+    /// Iterate over every masked email, fetching them a page at a time with
+    /// `MaskedEmail/query` as the iterator advances, so large accounts
+    /// aren't loaded into memory at once. Stops after the first error.
+    ///
+    /// Aliases created or destroyed while iterating may be skipped or seen
+    /// twice, since pages are taken by position.
+    pub fn iter_masked_emails(
+        &self,
+        account_id: &AccountId,
+    ) -> impl Iterator<Item = Result<MaskedEmail, FastmailError>> + '_ {
+        self.paged_masked_emails(account_id, PAGE_SIZE)
+    }
+
+    fn paged_masked_emails(
+        &self,
+        account_id: &AccountId,
+        page_size: usize,
+    ) -> MaskedEmailPages<'_> {
+        MaskedEmailPages {
+            client: self,
+            account_id: account_id.clone(),
+            page_size,
+            position: 0,
+            page: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Up to `limit` masked emails starting at `position`, queried and
+    /// fetched in one request.
+    fn masked_email_page(
+        &self,
+        account_id: &AccountId,
+        position: usize,
+        limit: usize,
+    ) -> Result<Vec<MaskedEmail>, FastmailError> {
+        self.query_masked_emails(account_id, &[], position, limit)
+            .map(|page| page.emails)
+    }
+
+    /// Up to `limit` masked emails in any of `states` (all of them when
+    /// empty) starting at `position`, with the total number of matches for
+    /// showing e.g. "51-100 of 312". The state filter is applied by the server.
+    pub fn query_masked_emails(
+        &self,
+        account_id: &AccountId,
+        states: &[&str],
+        position: usize,
+        limit: usize,
+    ) -> Result<QueryPage, FastmailError> {
+        let mut query = serde_json::json!({
+            "accountId": account_id,
+            "position": position,
+            "limit": limit,
+            "calculateTotal": true
+        });
+        match states {
+            [] => {}
+            [state] => query["filter"] = serde_json::json!({"state": state}),
+            states => {
+                let conditions: Vec<_> =
+                    states.iter().map(|state| serde_json::json!({"state": state})).collect();
+                query["filter"] = serde_json::json!({"operator": "OR", "conditions": conditions});
+            }
+        }
+        let mut request = JmapRequest::new();
+        let query_id = request.call("MaskedEmail/query", &[MASKED_EMAIL_CAPABILITY], query);
+        let call_id = request.call(
+            "MaskedEmail/get",
+            &[MASKED_EMAIL_CAPABILITY],
+            serde_json::json!({
+                "accountId": account_id,
+                "#ids": {"resultOf": query_id, "name": "MaskedEmail/query", "path": "/ids"}
+            }),
+        );
+
+        let jmap = self.send(&request)?;
+
+        let (Some(query), Some(list)) = (
+            jmap.response_for("MaskedEmail/query", &query_id),
+            jmap.response_for("MaskedEmail/get", &call_id)
+                .and_then(|result| result.get("list")),
+        ) else {
+            return Err(FastmailError::Api(format!("Unexpected response: {:?}", jmap)));
+        };
+        let ids: Vec<&str> = query
+            .get("ids")
+            .and_then(|ids| ids.as_array())
+            .map(|ids| ids.iter().filter_map(|id| id.as_str()).collect())
+            .unwrap_or_default();
+        let mut emails = parse_masked_emails(list.clone())?;
+        // A get may answer in any order, so restore the query's
+        emails.sort_by_key(|e| {
+            e.id.as_ref()
+                .and_then(|id| ids.iter().position(|q| *q == id.as_ref()))
+                .unwrap_or(usize::MAX)
+        });
+        let as_usize = |name| {
+            query.get(name).and_then(|v| v.as_u64()).map(|v| v as usize)
+        };
+        Ok(QueryPage {
+            emails,
+            position: as_usize("position").unwrap_or(position),
+            total: as_usize("total"),
+            query_state: query
+                .get("queryState")
+                .and_then(|s| s.as_str())
+                .unwrap_or_default()
+                .to_string(),
+        })
+    }
+
+    /// Send a short message to `to`, e.g. a masked email, to check that it
+    /// forwards. Needs a token with the mail and submission capabilities;
+    /// the draft is deleted once submitted, so only the delivered copy remains.
+    pub fn send_test_message(&self, to: &str) -> Result<TestMessage, FastmailError> {
+        // Fetched first so a missing capability is named before anything is sent
+        let session = self.get_session()?;
+        let account_id = session
+            .primary_accounts
+            .get(JMAP_SUBMISSION_CAPABILITY)
+            .map(|id| AccountId::from(id.as_str()))
+            .ok_or_else(|| {
+                FastmailError::MissingCapability(JMAP_SUBMISSION_CAPABILITY.to_string())
+            })?;
+
+        // Who to send as, and where the message waits until it's submitted
+        let mut request = JmapRequest::new();
+        let identities_id = request.call(
+            "Identity/get",
+            &[JMAP_MAIL_CAPABILITY, JMAP_SUBMISSION_CAPABILITY],
+            serde_json::json!({"accountId": account_id}),
+        );
+        let drafts_id = request.call(
+            "Mailbox/query",
+            &[JMAP_MAIL_CAPABILITY],
+            serde_json::json!({"accountId": account_id, "filter": {"role": "drafts"}}),
+        );
+        let jmap = self.send(&request)?;
+        let identity = jmap
+            .response_for("Identity/get", &identities_id)
+            .and_then(|result| result.pointer("/list/0"))
+            .ok_or_else(|| FastmailError::NotFound("an identity to send from".to_string()))?;
+        let (Some(identity_id), Some(from)) = (
+            identity.get("id").and_then(|id| id.as_str()),
+            identity.get("email").and_then(|email| email.as_str()),
+        ) else {
+            return Err(FastmailError::Parse(format!("Unexpected identity: {}", identity)));
+        };
+        let drafts = jmap
+            .response_for("Mailbox/query", &drafts_id)
+            .and_then(|result| result.pointer("/ids/0"))
+            .and_then(|id| id.as_str())
+            .ok_or_else(|| FastmailError::NotFound("a Drafts mailbox".to_string()))?;
+
+        let subject = format!("tmail test {:016x}", self.random());
+        let mut request = JmapRequest::new();
+        let email_id = request.call(
+            "Email/set",
+            &[JMAP_MAIL_CAPABILITY],
+            serde_json::json!({
+                "accountId": account_id,
+                "create": {"test": {
+                    "mailboxIds": {drafts: true},
+                    "keywords": {"$draft": true, "$seen": true},
+                    "from": [{"email": from}],
+                    "to": [{"email": to}],
+                    "subject": subject,
+                    "bodyValues": {"body": {
+                        "value": "Sent by 'tmail masked test' to check that this address forwards."
+                    }},
+                    "textBody": [{"partId": "body", "type": "text/plain"}]
+                }}
+            }),
+        );
+        let submission_id = request.call(
+            "EmailSubmission/set",
+            &[JMAP_MAIL_CAPABILITY, JMAP_SUBMISSION_CAPABILITY],
+            serde_json::json!({
+                "accountId": account_id,
+                "create": {"send": {"identityId": identity_id, "emailId": "#test"}},
+                "onSuccessDestroyEmail": ["#send"]
+            }),
+        );
+        let jmap = self.send(&request)?;
+        for (method, call_id, key) in [
+            ("Email/set", &email_id, "test"),
+            ("EmailSubmission/set", &submission_id, "send"),
+        ] {
+            let Some(result) = jmap.response_for(method, call_id) else {
+                return Err(FastmailError::Api(format!("Unexpected response: {:?}", jmap)));
+            };
+            if let Some(error) = result.get("notCreated").and_then(|n| n.get(key)) {
+                return Err(FastmailError::Api(set_error_message(error)));
+            }
+        }
+        Ok(TestMessage { subject, account_id })
+    }
+
+    /// Whether a message from `send_test_message` has been delivered.
+    pub fn test_message_arrived(&self, message: &TestMessage) -> Result<bool, FastmailError> {
+        let mut request = JmapRequest::new();
+        let call_id = request.call(
+            "Email/query",
+            &[JMAP_MAIL_CAPABILITY],
+            serde_json::json!({
+                "accountId": message.account_id,
+                "filter": {"subject": message.subject, "notKeyword": "$draft"},
+                "limit": 1
+            }),
+        );
+        let jmap = self.send(&request)?;
+        jmap.response_for("Email/query", &call_id)
+            .and_then(|result| result.get("ids"))
+            .and_then(|ids| ids.as_array())
+            .map(|ids| !ids.is_empty())
+            .ok_or_else(|| FastmailError::Api(format!("Unexpected response: {:?}", jmap)))
+    }
+
+    /// Look up a masked email by its address.
+    pub fn find_by_email(
+        &self,
+        account_id: &AccountId,
+        email: &str,
+    ) -> Result<MaskedEmail, FastmailError> {
+        self.list_masked_emails_with_state(account_id)?
+            .find(email)
+            .cloned()
+            .ok_or_else(|| FastmailError::NotFound(format!("masked email '{}'", email)))
+    }
+
+    /// Change a masked email's description, domain and/or url.
+    pub fn update_masked_email(
+        &self,
+        account_id: &AccountId,
+        id: &MaskedEmailId,
+        description: UpdateSpec,
+        for_domain: UpdateSpec,
+        url: UpdateSpec,
+    ) -> Result<(), FastmailError> {
+        let patch = update_patch(description, for_domain, url)?;
+        self.update(account_id, id, patch).map(|_| ())
+    }
+
+    /// Like `update_masked_email`, returning the account's new
+    /// `MaskedEmail` state string for callers keeping a synced copy.
+    pub fn update_masked_email_with_state(
+        &self,
+        account_id: &AccountId,
+        id: &MaskedEmailId,
+        description: UpdateSpec,
+        for_domain: UpdateSpec,
+        url: UpdateSpec,
+    ) -> Result<String, FastmailError> {
+        let patch = update_patch(description, for_domain, url)?;
+        required_state(self.update(account_id, id, patch)?)
+    }
+
+    /// Set a masked email's `state` (`enabled`, `disabled` or `deleted`),
+    /// returning the account's new `MaskedEmail` state string.
+    pub fn set_masked_email_state(
+        &self,
+        account_id: &AccountId,
+        id: &MaskedEmailId,
+        state: &str,
+    ) -> Result<String, FastmailError> {
+        required_state(self.update(account_id, id, serde_json::json!({ "state": state }))?)
+    }
+
+    pub fn enable_masked_email(
+        &self,
+        account_id: &AccountId,
+        id: &MaskedEmailId,
+    ) -> Result<(), FastmailError> {
+        self.update(account_id, id, serde_json::json!({ "state": "enabled" })).map(|_| ())
+    }
+
+    pub fn delete_masked_email(
+        &self,
+        account_id: &AccountId,
+        id: &MaskedEmailId,
+    ) -> Result<(), FastmailError> {
+        self.update(account_id, id, serde_json::json!({ "state": "disabled" })).map(|_| ())
+    }
+
+    pub fn destroy_masked_email(
+        &self,
+        account_id: &AccountId,
+        id: &MaskedEmailId,
+    ) -> Result<(), FastmailError> {
+        self.update(account_id, id, serde_json::json!({ "state": "deleted" })).map(|_| ())
+    }
+
+    /// Destroy several masked emails in a single `MaskedEmail/set` call,
+    /// returning one result per id in input order.
+    pub fn destroy_masked_emails(
+        &self,
+        account_id: &AccountId,
+        ids: &[MaskedEmailId],
+    ) -> Result<Vec<Result<(), FastmailError>>, FastmailError> {
+        self.set_masked_email_states(account_id, ids, "deleted")
+    }
+
+    /// Set the `state` of several masked emails in a single
+    /// `MaskedEmail/set` call, returning one result per id in input order.
+    pub fn set_masked_email_states(
+        &self,
+        account_id: &AccountId,
+        ids: &[MaskedEmailId],
+        state: &str,
+    ) -> Result<Vec<Result<(), FastmailError>>, FastmailError> {
+        let update: serde_json::Map<String, serde_json::Value> = ids
+            .iter()
+            .map(|id| (id.to_string(), serde_json::json!({ "state": state })))
+            .collect();
+        let mut request = JmapRequest::new();
+        let call_id = request.call(
+            "MaskedEmail/set",
+            &[MASKED_EMAIL_CAPABILITY],
+            serde_json::json!({
+                "accountId": account_id,
+                "update": update
+            }),
+        );
+
+        let jmap = self.send(&request)?;
+
+        if let Some(result) = jmap.response_for("MaskedEmail/set", &call_id) {
+            let results = ids
+                .iter()
+                .map(|id| {
+                    if result.get("updated").and_then(|u| u.get(id.as_ref())).is_some() {
+                        Ok(())
+                    } else if let Some(error) =
+                        result.get("notUpdated").and_then(|n| n.get(id.as_ref()))
+                    {
+                        Err(FastmailError::Api(set_error_message(error)))
+                    } else {
+                        Err(FastmailError::Api(format!("No result for {}", id)))
+                    }
+                })
+                .collect();
+            return Ok(results);
+        }
+
+        Err(FastmailError::Api(format!(
+            "Unexpected response: {:?}",
+            jmap
+        )))
+    }
+
+    /// Apply a patch, returning the `newState` if the server sent one.
+    fn update(
+        &self,
+        account_id: &AccountId,
+        id: &MaskedEmailId,
+        patch: serde_json::Value,
+    ) -> Result<Option<String>, FastmailError> {
+        let mut request = JmapRequest::new();
+        let call_id = request.call(
+            "MaskedEmail/set",
+            &[MASKED_EMAIL_CAPABILITY],
+            serde_json::json!({
+                "accountId": account_id,
+                "update": {
+                    id.as_ref(): patch
+                }
+            }),
+        );
+
+        let jmap = self.send(&request)?;
+
+        if let Some(result) = jmap.response_for("MaskedEmail/set", &call_id) {
+            if result.get("updated").and_then(|u| u.get(id.as_ref())).is_some() {
+                let new_state = result.get("newState").and_then(|s| s.as_str());
+                return Ok(new_state.map(str::to_string));
+            }
+            if let Some(error) = result.get("notUpdated").and_then(|n| n.get(id.as_ref())) {
+                return Err(FastmailError::Api(set_error_message(error)));
+            }
+        }
+
+        Err(FastmailError::Api(format!(
+            "Unexpected response: {:?}",
+            jmap
+        )))
+    }
+}
+
+/// The masked email operations the CLI is built on, so code embedding the
+/// crate can swap `FastmailClient` for an in-memory fake in its own tests.
+pub trait MaskedEmailStore {
+    fn list(&self, account_id: &AccountId) -> Result<Vec<MaskedEmail>, FastmailError>;
+
+    fn create(
+        &self,
+        account_id: &AccountId,
+        spec: &CreateSpec,
+    ) -> Result<MaskedEmail, FastmailError>;
+
+    /// Create several masked emails, one result per spec in input order.
+    /// Defaults to one `create` per spec.
+    fn create_many(
+        &self,
+        account_id: &AccountId,
+        specs: &[CreateSpec],
+    ) -> Result<Vec<Result<MaskedEmail, BatchItemError>>, FastmailError> {
+        Ok(specs
+            .iter()
+            .enumerate()
+            .map(|(i, spec)| {
+                self.create(account_id, spec)
+                    .map_err(|e| BatchItemError::new(i, spec, e))
+            })
+            .collect())
+    }
+
+    /// Look up a masked email by its address, with `NotFound` on a miss.
+    fn get(&self, account_id: &AccountId, email: &str) -> Result<MaskedEmail, FastmailError>;
+
+    fn update(
+        &self,
+        account_id: &AccountId,
+        id: &MaskedEmailId,
+        description: UpdateSpec,
+        for_domain: UpdateSpec,
+        url: UpdateSpec,
+    ) -> Result<(), FastmailError>;
+
+    fn enable(&self, account_id: &AccountId, id: &MaskedEmailId) -> Result<(), FastmailError>;
+
+    fn disable(&self, account_id: &AccountId, id: &MaskedEmailId) -> Result<(), FastmailError>;
+
+    fn destroy(&self, account_id: &AccountId, id: &MaskedEmailId) -> Result<(), FastmailError>;
+
+    /// Destroy several masked emails, one result per id in input order.
+    /// Defaults to one `destroy` per id.
+    fn destroy_many(
+        &self,
+        account_id: &AccountId,
+        ids: &[MaskedEmailId],
+    ) -> Result<Vec<Result<(), FastmailError>>, FastmailError> {
+        Ok(ids.iter().map(|id| self.destroy(account_id, id)).collect())
+    }
+
+    /// Enable (`enabled`) or disable (`disabled`) several masked emails,
+    /// one result per id in input order. Defaults to one call per id.
+    fn set_state_many(
+        &self,
+        account_id: &AccountId,
+        ids: &[MaskedEmailId],
+        state: &str,
+    ) -> Result<Vec<Result<(), FastmailError>>, FastmailError> {
+        let set = |id| match state {
+            "enabled" => self.enable(account_id, id),
+            "disabled" => self.disable(account_id, id),
+            "deleted" => self.destroy(account_id, id),
+            _ => Err(FastmailError::InvalidInput(format!("unknown state '{}'", state))),
+        };
+        Ok(ids.iter().map(set).collect())
+    }
+}
+
+impl MaskedEmailStore for FastmailClient {
+    fn list(&self, account_id: &AccountId) -> Result<Vec<MaskedEmail>, FastmailError> {
+        self.list_masked_emails(account_id)
+    }
+
+    fn create(
+        &self,
+        account_id: &AccountId,
+        spec: &CreateSpec,
+    ) -> Result<MaskedEmail, FastmailError> {
+        self.create_masked_email_with(account_id, spec)
+    }
+
+    fn create_many(
+        &self,
+        account_id: &AccountId,
+        specs: &[CreateSpec],
+    ) -> Result<Vec<Result<MaskedEmail, BatchItemError>>, FastmailError> {
+        self.create_masked_emails(account_id, specs)
+    }
+
+    fn get(&self, account_id: &AccountId, email: &str) -> Result<MaskedEmail, FastmailError> {
+        self.find_by_email(account_id, email)
+    }
+
+    fn update(
+        &self,
+        account_id: &AccountId,
+        id: &MaskedEmailId,
+        description: UpdateSpec,
+        for_domain: UpdateSpec,
+        url: UpdateSpec,
+    ) -> Result<(), FastmailError> {
+        self.update_masked_email(account_id, id, description, for_domain, url)
+    }
+
+    fn enable(&self, account_id: &AccountId, id: &MaskedEmailId) -> Result<(), FastmailError> {
+        self.enable_masked_email(account_id, id)
+    }
+
+    fn disable(&self, account_id: &AccountId, id: &MaskedEmailId) -> Result<(), FastmailError> {
+        self.delete_masked_email(account_id, id)
+    }
+
+    fn destroy(&self, account_id: &AccountId, id: &MaskedEmailId) -> Result<(), FastmailError> {
+        self.destroy_masked_email(account_id, id)
+    }
+
+    fn destroy_many(
+        &self,
+        account_id: &AccountId,
+        ids: &[MaskedEmailId],
+    ) -> Result<Vec<Result<(), FastmailError>>, FastmailError> {
+        self.destroy_masked_emails(account_id, ids)
+    }
+
+    fn set_state_many(
+        &self,
+        account_id: &AccountId,
+        ids: &[MaskedEmailId],
+        state: &str,
+    ) -> Result<Vec<Result<(), FastmailError>>, FastmailError> {
+        self.set_masked_email_states(account_id, ids, state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::prelude::*;
+
+    fn get_test_token() -> String {
+        std::env::var("FASTMAIL_TOKEN").expect("FASTMAIL_TOKEN env var required for tests")
+    }
+
+    fn mock_client(server: &MockServer) -> FastmailClient {
+        FastmailClient::builder("token")
+            .session_url(server.url("/jmap/session"))
+            .api_url(server.url("/jmap/api/"))
+            .max_retries(0)
+            .build()
+            .unwrap()
+    }
+
+    /// Mock the JMAP API endpoint to answer with a single method response.
+    fn mock_method_response<'a>(
+        server: &'a MockServer,
+        method: &str,
+        call_id: &str,
+        result: serde_json::Value,
+    ) -> httpmock::Mock<'a> {
+        let body = serde_json::json!({
+            "methodResponses": [[method, result, call_id]],
+            "sessionState": "s1"
+        });
+        server.mock(|when, then| {
+            when.method(POST).path("/jmap/api/");
+            then.status(200).json_body(body);
+        })
+    }
+
+    const SESSION_BODY: &str = r#"{
+        "capabilities": {"urn:ietf:params:jmap:core": {}, "https://www.fastmail.com/dev/maskedemail": {}},
+        "primaryAccounts": {"https://www.fastmail.com/dev/maskedemail": "u123"},
+        "username": "user@example.com",
+        "apiUrl": "https://api.fastmail.com/jmap/api/"
+    }"#;
+
+    #[test]
+    fn test_session_resolves_account_id() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(GET)
+                .path("/jmap/session")
+                .header("authorization", "Bearer token");
+            then.status(200).body(SESSION_BODY);
+        });
+        let client = mock_client(&server);
+        assert_eq!(client.get_account_id().unwrap().as_ref(), "u123");
+        mock.assert();
+    }
+
+    #[test]
+    fn test_masked_email_limit() {
+        let session: SessionResponse = serde_json::from_str(SESSION_BODY).unwrap();
+        assert_eq!(session.masked_email_limit(), None);
+
+        let session: SessionResponse = serde_json::from_value(serde_json::json!({
+            "capabilities": {"https://www.fastmail.com/dev/maskedemail": {}},
+            "accounts": {"u123": {
+                "accountCapabilities": {"https://www.fastmail.com/dev/maskedemail": {"maxMaskedEmails": 500}}
+            }},
+            "primaryAccounts": {"https://www.fastmail.com/dev/maskedemail": "u123"}
+        }))
+        .unwrap();
+        assert_eq!(session.masked_email_limit(), Some(500));
+    }
+
+    #[test]
+    fn test_session_is_fetched_once() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(GET).path("/jmap/session");
+            then.status(200).body(SESSION_BODY);
+        });
+        let client = mock_client(&server);
+        client.get_account_id().unwrap();
+        client.get_account_id().unwrap();
+        mock.assert_hits(1);
+
+        client.refresh_session().unwrap();
+        mock.assert_hits(2);
+    }
+
+    #[test]
+    fn test_session_unauthorized() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/jmap/session");
+            then.status(401).body("Unauthorized");
+        });
+        let client = mock_client(&server);
+        let err = client.get_session().unwrap_err();
+        assert!(matches!(err, FastmailError::Auth(401, _)));
+        assert_eq!(err.kind(), "auth");
+        assert_eq!(err.status(), Some(401));
+    }
+
+    #[test]
+    fn test_server_error_is_not_auth() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(POST).path("/jmap/api/");
+            then.status(503).body("Service Unavailable");
+        });
+        let client = mock_client(&server);
+        let err = client.list_masked_emails(&"u1".into()).unwrap_err();
+        assert!(matches!(err, FastmailError::Status(503, _)), "{:?}", err);
+        assert_eq!(err.kind(), "status");
+        assert_eq!(err.status(), Some(503));
+    }
+
+    #[test]
+    fn test_create_masked_email_success() {
+        let server = MockServer::start();
+        // Without retries the description goes untagged, in a single write
+        let create = server.mock(|when, then| {
+            when.method(POST)
+                .path("/jmap/api/")
+                .body_contains(r#""create""#)
+                .body_contains(r#""description":"shop""#);
+            then.status(200).json_body(serde_json::json!({
+                "methodResponses": [["MaskedEmail/set", {
+                    "accountId": "u1",
+                    "created": {
+                        "new": {"id": "m1", "email": "abc@fastmail.com", "state": "enabled"}
+                    }
+                }, "set-0"]]
+            }));
+        });
+        let client = mock_client(&server);
+        let created = client
+            .create_masked_email(&"u1".into(), Some("shop"), Some("example.com"))
+            .unwrap();
+        assert_eq!(created.id, Some("m1".into()));
+        assert_eq!(created.email, "abc@fastmail.com");
+        // Properties the server doesn't echo back come from the request
+        assert_eq!(created.description.as_deref(), Some("shop"));
+        assert_eq!(created.for_domain.as_deref(), Some("example.com"));
+        assert_eq!(created.retry_tag(), None);
+        create.assert_hits(1);
+    }
+
+    #[test]
+    fn test_create_with_retries_removes_its_tag() {
+        let server = MockServer::start();
+        let tag = format!("[tmail#{:016x}]", Jitter::new(7).next());
+        let create = server.mock(|when, then| {
+            when.method(POST)
+                .path("/jmap/api/")
+                .body_contains(format!(r#""description":"shop {}""#, tag));
+            then.status(200).json_body(serde_json::json!({
+                "methodResponses": [["MaskedEmail/set", {
+                    "created": {"new": {"id": "m1", "email": "abc@fastmail.com"}}
+                }, "set-0"]]
+            }));
+        });
+        let untag = server.mock(|when, then| {
+            when.method(POST)
+                .path("/jmap/api/")
+                .body_contains(r#""update":{"m1":{"description":"shop"}}"#);
+            then.status(200).json_body(serde_json::json!({
+                "methodResponses": [["MaskedEmail/set", {"updated": {"m1": null}}, "set-0"]]
+            }));
+        });
+        let client = FastmailClient::builder("token")
+            .api_url(server.url("/jmap/api/"))
+            .jitter_seed(7)
+            .build()
+            .unwrap();
+        let created = client.create_masked_email(&"u1".into(), Some("shop"), None).unwrap();
+        assert_eq!(created.description.as_deref(), Some("shop"));
+        assert_eq!(created.retry_tag(), None);
+        create.assert_hits(1);
+        untag.assert_hits(1);
+    }
+
+    #[test]
+    fn test_create_reports_a_tag_it_could_not_remove() {
+        let server = MockServer::start();
+        let tag = format!("[tmail#{:016x}]", Jitter::new(7).next());
+        server.mock(|when, then| {
+            when.method(POST).path("/jmap/api/").body_contains(r#""create""#);
+            then.status(200).json_body(serde_json::json!({
+                "methodResponses": [["MaskedEmail/set", {
+                    "created": {"new": {"id": "m1", "email": "abc@fastmail.com"}}
+                }, "set-0"]]
+            }));
+        });
+        let untag = server.mock(|when, then| {
+            when.method(POST).path("/jmap/api/").body_contains(r#""update""#);
+            then.status(200).json_body(serde_json::json!({
+                "methodResponses": [["MaskedEmail/set", {
+                    "notUpdated": {"m1": {"type": "forbidden"}}
+                }, "set-0"]]
+            }));
+        });
+        let client = FastmailClient::builder("token")
+            .api_url(server.url("/jmap/api/"))
+            .jitter_seed(7)
+            .build()
+            .unwrap();
+        // The alias exists, so the create still succeeds, with the tag flagged
+        let created = client.create_masked_email(&"u1".into(), Some("shop"), None).unwrap();
+        assert_eq!(created.email, "abc@fastmail.com");
+        assert_eq!(created.description, Some(format!("shop {}", tag)));
+        assert_eq!(created.retry_tag(), Some(tag.as_str()));
+        untag.assert_hits(1);
+
+        // Other bracketed text isn't mistaken for a tag
+        let plain = MaskedEmail {
+            description: Some("shop [tmail#notatag]".to_string()),
+            ..created
+        };
+        assert_eq!(plain.retry_tag(), None);
+    }
+
+    #[test]
+    fn test_create_masked_email_with_state() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(POST).path("/jmap/api/").body_contains(r#""state":"disabled""#);
+            then.status(200).json_body(serde_json::json!({
+                "methodResponses": [["MaskedEmail/set", {
+                    "accountId": "u1",
+                    "created": {"new": {"id": "m1", "email": "abc@fastmail.com"}}
+                }, "set-0"]],
+                "sessionState": "s1"
+            }));
+        });
+        let client = mock_client(&server);
+        let spec = CreateSpec {
+            state: Some("disabled".to_string()),
+            ..Default::default()
+        };
+        let created = client.create_masked_email_with(&"u1".into(), &spec).unwrap();
+        assert_eq!(created.state.as_deref(), Some("disabled"));
+        mock.assert();
+
+        let spec = CreateSpec {
+            state: Some("deleted".to_string()),
+            ..Default::default()
+        };
+        let err = client.create_masked_email_with(&"u1".into(), &spec).unwrap_err();
+        assert!(matches!(err, FastmailError::InvalidInput(_)));
+        assert!(err.to_string().contains("can't be created as deleted"));
+        assert!(validate_create_state("pending").is_ok());
+        assert!(validate_create_state("paused").is_err());
+    }
+
+    #[test]
+    fn test_iter_masked_emails_pages_lazily() {
+        let server = MockServer::start();
+        let page = |position: usize, emails: serde_json::Value| {
+            server.mock(|when, then| {
+                when.method(POST)
+                    .path("/jmap/api/")
+                    .body_contains(format!(r#""position":{}"#, position));
+                then.status(200).json_body(serde_json::json!({
+                    "methodResponses": [
+                        ["MaskedEmail/query", {"accountId": "u1", "ids": []}, "query-0"],
+                        ["MaskedEmail/get", {"accountId": "u1", "list": emails}, "get-1"]
+                    ],
+                    "sessionState": "s1"
+                }));
+            })
+        };
+        let first = page(
+            0,
+            serde_json::json!([{"email": "a@fastmail.com"}, {"email": "b@fastmail.com"}]),
+        );
+        let second = page(2, serde_json::json!([{"email": "c@fastmail.com"}]));
+        let client = mock_client(&server);
+
+        let mut emails = client.paged_masked_emails(&"u1".into(), 2);
+        assert_eq!(emails.next().unwrap().unwrap().email, "a@fastmail.com");
+        second.assert_hits(0);
+        let rest: Vec<String> = emails.map(|e| e.unwrap().email).collect();
+        assert_eq!(rest, ["b@fastmail.com", "c@fastmail.com"]);
+        // The short second page ends iteration without a third request
+        first.assert_hits(1);
+        second.assert_hits(1);
+    }
+
+    #[test]
+    fn test_query_masked_emails_returns_total() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/jmap/api/")
+                .body_contains(r#""calculateTotal":true"#)
+                .body_contains(r#""conditions":[{"state":"enabled"},{"state":"disabled"}]"#)
+                .body_contains(r#""operator":"OR""#)
+                .body_contains(r#""position":50"#);
+            then.status(200).json_body(serde_json::json!({
+                "methodResponses": [
+                    ["MaskedEmail/query", {
+                        "accountId": "u1",
+                        "queryState": "q7",
+                        "position": 50,
+                        "total": 312,
+                        "ids": ["m2", "m1"]
+                    }, "query-0"],
+                    ["MaskedEmail/get", {"accountId": "u1", "list": [
+                        {"id": "m1", "email": "a@fastmail.com"},
+                        {"id": "m2", "email": "b@fastmail.com"}
+                    ]}, "get-1"]
+                ],
+                "sessionState": "s1"
+            }));
+        });
+        let client = mock_client(&server);
+
+        let page = client
+            .query_masked_emails(&"u1".into(), &["enabled", "disabled"], 50, 2)
+            .unwrap();
+        mock.assert();
+        assert_eq!(page.position, 50);
+        assert_eq!(page.total, Some(312));
+        assert_eq!(page.query_state, "q7");
+        let emails: Vec<&str> = page.emails.iter().map(|e| e.email.as_str()).collect();
+        assert_eq!(emails, ["b@fastmail.com", "a@fastmail.com"]);
+    }
+
+    #[test]
+    fn test_send_test_message() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/jmap/session");
+            then.status(200).json_body(serde_json::json!({
+                "capabilities": {
+                    "urn:ietf:params:jmap:core": {},
+                    "urn:ietf:params:jmap:mail": {},
+                    "urn:ietf:params:jmap:submission": {}
+                },
+                "primaryAccounts": {"urn:ietf:params:jmap:submission": "u1"}
+            }));
+        });
+        let lookup = server.mock(|when, then| {
+            when.method(POST).path("/jmap/api/").body_contains("Identity/get");
+            then.status(200).json_body(serde_json::json!({
+                "methodResponses": [
+                    ["Identity/get", {"list": [{"id": "i1", "email": "me@fastmail.com"}]}, "get-0"],
+                    ["Mailbox/query", {"ids": ["drafts1"]}, "query-1"]
+                ]
+            }));
+        });
+        let submit = server.mock(|when, then| {
+            when.method(POST)
+                .path("/jmap/api/")
+                .body_contains(r#""mailboxIds":{"drafts1":true}"#)
+                .body_contains(r#""to":[{"email":"abc@fastmail.com"}]"#)
+                .body_contains(r#""identityId":"i1""#)
+                .body_contains("urn:ietf:params:jmap:submission");
+            then.status(200).json_body(serde_json::json!({
+                "methodResponses": [
+                    ["Email/set", {"created": {"test": {"id": "e1"}}}, "set-0"],
+                    ["EmailSubmission/set", {"created": {"send": {"id": "s1"}}}, "set-1"]
+                ]
+            }));
+        });
+        let arrived = server.mock(|when, then| {
+            when.method(POST).path("/jmap/api/").body_contains("Email/query");
+            then.status(200).json_body(serde_json::json!({
+                "methodResponses": [["Email/query", {"ids": ["e2"]}, "query-0"]]
+            }));
+        });
+        let client = mock_client(&server);
+
+        let message = client.send_test_message("abc@fastmail.com").unwrap();
+        assert!(message.subject.starts_with("tmail test "));
+        assert_eq!(message.account_id.as_ref(), "u1");
+        assert!(client.test_message_arrived(&message).unwrap());
+        lookup.assert();
+        submit.assert();
+        arrived.assert();
+    }
+
+    #[test]
+    fn test_send_test_message_needs_submission() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/jmap/session");
+            then.status(200).body(SESSION_BODY);
+        });
+        let api = server.mock(|when, then| {
+            when.method(POST).path("/jmap/api/");
+            then.status(500);
+        });
+        let client = mock_client(&server);
+
+        let err = client.send_test_message("abc@fastmail.com").unwrap_err();
+        let FastmailError::MissingCapability(urn) = err else {
+            panic!("expected a missing capability, got {:?}", err);
+        };
+        assert_eq!(urn, JMAP_SUBMISSION_CAPABILITY);
+        api.assert_hits(0);
+    }
+
+    #[test]
+    fn test_create_masked_email_not_created() {
+        let server = MockServer::start();
+        mock_method_response(
+            &server,
+            "MaskedEmail/set",
+            "set-0",
+            serde_json::json!({
+                "accountId": "u1",
+                "notCreated": {"new": {"type": "overQuota", "description": "Too many aliases"}}
+            }),
+        );
+        let client = mock_client(&server);
+        let err = client.create_masked_email(&"u1".into(), None, None).unwrap_err();
+        assert!(matches!(err, FastmailError::Api(_)));
+        assert!(err.to_string().contains("overQuota"));
+    }
+
+    #[test]
+    fn test_delete_masked_email_success() {
+        let server = MockServer::start();
+        mock_method_response(
+            &server,
+            "MaskedEmail/set",
+            "set-0",
+            serde_json::json!({"accountId": "u1", "updated": {"m1": null}}),
+        );
+        let client = mock_client(&server);
+        assert!(client.delete_masked_email(&"u1".into(), &"m1".into()).is_ok());
+    }
+
+    #[test]
+    fn test_set_masked_email_state_returns_new_state() {
+        let server = MockServer::start();
+        mock_method_response(
+            &server,
+            "MaskedEmail/set",
+            "set-0",
+            serde_json::json!({
+                "accountId": "u1",
+                "oldState": "41",
+                "newState": "42",
+                "updated": {"m1": null}
+            }),
+        );
+        let client = mock_client(&server);
+        let state = client
+            .set_masked_email_state(&"u1".into(), &"m1".into(), "disabled")
+            .unwrap();
+        assert_eq!(state, "42");
+    }
+
+    #[test]
+    fn test_delete_masked_email_not_updated() {
+        let server = MockServer::start();
+        mock_method_response(
+            &server,
+            "MaskedEmail/set",
+            "set-0",
+            serde_json::json!({"accountId": "u1", "notUpdated": {"m1": {"type": "notFound"}}}),
+        );
+        let client = mock_client(&server);
+        let err = client.delete_masked_email(&"u1".into(), &"m1".into()).unwrap_err();
+        assert!(matches!(err, FastmailError::Api(_)));
+        assert!(err.to_string().contains("notFound"));
+    }
+
+    #[test]
+    fn test_enable_masked_email_sends_state() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/jmap/api/")
+                .body_contains(r#""update":{"m1":{"state":"enabled"}}"#);
+            then.status(200).json_body(serde_json::json!({
+                "methodResponses": [["MaskedEmail/set", {"accountId": "u1", "updated": {"m1": null}}, "set-0"]],
+                "sessionState": "s1"
+            }));
+        });
+        let client = mock_client(&server);
+        assert!(client.enable_masked_email(&"u1".into(), &"m1".into()).is_ok());
+        mock.assert();
+    }
+
+    #[test]
+    fn test_list_masked_emails_raw_keeps_unknown_fields() {
+        let server = MockServer::start();
+        mock_method_response(
+            &server,
+            "MaskedEmail/get",
+            "get-0",
+            serde_json::json!({
+                "accountId": "u1",
+                "state": "1",
+                "list": [{"id": "m1", "email": "abc@fastmail.com", "futureField": {"x": 1}}]
+            }),
+        );
+        let client = mock_client(&server);
+        let list = client.list_masked_emails_raw(&"u1".into()).unwrap();
+        assert_eq!(list[0]["futureField"]["x"], 1);
+        assert_eq!(list.as_array().map(Vec::len), Some(1));
+    }
+
+    #[test]
+    fn test_parse_error_quotes_offending_entry() {
+        let server = MockServer::start();
+        mock_method_response(
+            &server,
+            "MaskedEmail/get",
+            "get-0",
+            serde_json::json!({
+                "accountId": "u1",
+                "state": "1",
+                "list": [
+                    {"id": "m1", "email": "ok@fastmail.com"},
+                    {"id": "m2", "email": 42, "url": "x".repeat(500)}
+                ]
+            }),
+        );
+        let client = mock_client(&server);
+        let err = client.list_masked_emails(&"u1".into()).unwrap_err();
+        assert!(matches!(err, FastmailError::Parse(_)));
+        let message = err.to_string();
+        assert!(message.contains(r#""email":42"#), "{}", message);
+        assert!(message.ends_with('…'), "{}", message);
+        assert!(message.len() < 400, "{}", message);
+    }
+
+    #[test]
+    fn test_list_result_helpers() {
+        let list: Vec<MaskedEmail> = serde_json::from_value(serde_json::json!([
+            {"email": "a@fastmail.com", "state": "enabled", "forDomain": "shop.com"},
+            {"email": "B@fastmail.com", "state": "disabled", "forDomain": "Shop.com."},
+            {"email": "c@fastmail.com", "state": "enabled", "forDomain": "mail.shop.com"}
+        ]))
+        .unwrap();
+        let result = ListResult {
+            emails: list,
+            state: "1".to_string(),
+        };
+        let emails = |iter: Vec<&MaskedEmail>| -> Vec<String> {
+            iter.iter().map(|e| e.email.clone()).collect()
+        };
+        assert_eq!(emails(result.enabled().collect()), ["a@fastmail.com", "c@fastmail.com"]);
+        assert_eq!(
+            emails(result.by_domain("shop.com").collect()),
+            ["a@fastmail.com", "B@fastmail.com"]
+        );
+        assert_eq!(result.find("b@FASTMAIL.com").map(|e| e.email.as_str()), Some("B@fastmail.com"));
+        assert!(result.find("d@fastmail.com").is_none());
+        let unstated = ListResult::from(result.emails.clone());
+        assert!(unstated.state.is_empty());
+        assert!(unstated.find("A@fastmail.com").is_some());
+        assert_eq!((&result).into_iter().count(), 3);
+        assert_eq!(result.into_iter().last().unwrap().email, "c@fastmail.com");
+    }
+
+    #[test]
+    fn test_get_masked_email_by_id() {
+        let server = MockServer::start();
+        let found = server.mock(|when, then| {
+            when.method(POST).path("/jmap/api/").body_contains(r#""ids":["m1"]"#);
+            then.status(200).json_body(serde_json::json!({
+                "methodResponses": [["MaskedEmail/get", {
+                    "accountId": "u1",
+                    "list": [{"id": "m1", "email": "abc@fastmail.com", "state": "pending"}],
+                    "notFound": []
+                }, "get-0"]],
+                "sessionState": "s1"
+            }));
+        });
+        server.mock(|when, then| {
+            when.method(POST).path("/jmap/api/").body_contains(r#""ids":["gone"]"#);
+            then.status(200).json_body(serde_json::json!({
+                "methodResponses": [["MaskedEmail/get", {
+                    "accountId": "u1",
+                    "list": [],
+                    "notFound": ["gone"]
+                }, "get-0"]],
+                "sessionState": "s1"
+            }));
+        });
+        let client = mock_client(&server);
+        let email = client.get_masked_email(&"u1".into(), &"m1".into()).unwrap();
+        assert_eq!(email.state.as_deref(), Some("pending"));
+        found.assert();
+
+        let err = client.get_masked_email(&"u1".into(), &"gone".into()).unwrap_err();
+        assert!(matches!(err, FastmailError::NotFound(_)));
+    }
+
+    #[test]
+    fn test_list_masked_emails_empty_account() {
+        let server = MockServer::start();
+        mock_method_response(
+            &server,
+            "MaskedEmail/get",
+            "get-0",
+            serde_json::json!({"accountId": "u1", "state": "1", "list": [], "notFound": []}),
+        );
+        let client = mock_client(&server);
+        assert!(client.list_masked_emails(&"u1".into()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_list_decompresses_gzip_response() {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let list: Vec<serde_json::Value> = (0..2000)
+            .map(|i| {
+                serde_json::json!({"id": format!("m{}", i), "email": format!("a{}@fm.com", i)})
+            })
+            .collect();
+        let body = serde_json::json!({
+            "methodResponses": [
+                ["MaskedEmail/get", {"accountId": "u1", "state": "1", "list": list}, "get-0"]
+            ],
+            "sessionState": "s1"
+        });
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(body.to_string().as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(POST).path("/jmap/api/").header("accept-encoding", "gzip");
+            then.status(200)
+                .header("content-type", "application/json")
+                .header("content-encoding", "gzip")
+                .body(compressed);
+        });
+        let client = mock_client(&server);
+        let emails = client.list_masked_emails(&"u1".into()).unwrap();
+        assert_eq!(emails.len(), 2000);
+        assert_eq!(emails[1999].email, "a1999@fm.com");
+        mock.assert();
+    }
+
+    #[test]
+    fn test_rejects_unexpected_call_id() {
+        let server = MockServer::start();
+        mock_method_response(
+            &server,
+            "MaskedEmail/get",
+            "other",
+            serde_json::json!({"accountId": "u1", "list": []}),
+        );
+        let client = mock_client(&server);
+        let err = client.list_masked_emails(&"u1".into()).unwrap_err();
+        assert!(matches!(err, FastmailError::Parse(_)));
+        assert!(err.to_string().contains("'other'"));
+    }
+
+    #[test]
+    fn test_list_masked_emails_with_state() {
+        let server = MockServer::start();
+        mock_method_response(
+            &server,
+            "MaskedEmail/get",
+            "get-0",
+            serde_json::json!({
+                "accountId": "u1",
+                "state": "st-42",
+                "list": [{"id": "m1", "email": "abc@fastmail.com", "state": "enabled"}]
+            }),
+        );
+        let client = mock_client(&server);
+        let result = client.list_masked_emails_with_state(&"u1".into()).unwrap();
+        assert_eq!(result.state, "st-42");
+        assert_eq!(result.emails.len(), 1);
+        assert_eq!(result.emails[0].email, "abc@fastmail.com");
+    }
+
+    #[test]
+    fn test_find_by_email() {
+        let server = MockServer::start();
+        mock_method_response(
+            &server,
+            "MaskedEmail/get",
+            "get-0",
+            serde_json::json!({
+                "accountId": "u1",
+                "state": "1",
+                "list": [{"id": "m1", "email": "abc@fastmail.com", "state": "enabled"}]
+            }),
+        );
+        let client = mock_client(&server);
+        let found = client.find_by_email(&"u1".into(), "ABC@fastmail.com").unwrap();
+        assert_eq!(found.id, Some("m1".into()));
+        let err = client.find_by_email(&"u1".into(), "xyz@fastmail.com").unwrap_err();
+        assert!(matches!(err, FastmailError::NotFound(_)));
+    }
+
+    #[test]
+    fn test_store_default_create_many() {
+        /// A store that creates sequential addresses and rejects empty domains.
+        struct FakeStore;
+
+        impl MaskedEmailStore for FakeStore {
+            fn list(&self, _: &AccountId) -> Result<Vec<MaskedEmail>, FastmailError> {
+                Ok(Vec::new())
+            }
+
+            fn create(
+                &self,
+                _: &AccountId,
+                spec: &CreateSpec,
+            ) -> Result<MaskedEmail, FastmailError> {
+                let domain = spec.for_domain.clone().filter(|d| !d.is_empty());
+                let Some(domain) = domain else {
+                    return Err(FastmailError::InvalidInput("no domain".to_string()));
+                };
+                Ok(serde_json::from_value(serde_json::json!({
+                    "email": format!("{}@fastmail.com", domain),
+                    "forDomain": domain
+                }))
+                .unwrap())
+            }
+
+            fn get(&self, _: &AccountId, email: &str) -> Result<MaskedEmail, FastmailError> {
+                Err(FastmailError::NotFound(email.to_string()))
+            }
+
+            fn update(
+                &self,
+                _: &AccountId,
+                _: &MaskedEmailId,
+                _: UpdateSpec,
+                _: UpdateSpec,
+                _: UpdateSpec,
+            ) -> Result<(), FastmailError> {
+                Ok(())
+            }
+
+            fn enable(&self, _: &AccountId, _: &MaskedEmailId) -> Result<(), FastmailError> {
+                Ok(())
+            }
+
+            fn disable(&self, _: &AccountId, _: &MaskedEmailId) -> Result<(), FastmailError> {
+                Ok(())
+            }
+
+            fn destroy(&self, _: &AccountId, _: &MaskedEmailId) -> Result<(), FastmailError> {
+                Ok(())
+            }
+        }
+
+        let spec = |domain: &str| CreateSpec {
+            for_domain: Some(domain.to_string()),
+            ..Default::default()
+        };
+        let store: &dyn MaskedEmailStore = &FakeStore;
+        let results = store
+            .create_many(&"u1".into(), &[spec("a.com"), spec(""), spec("b.com")])
+            .unwrap();
+        assert_eq!(results[0].as_ref().unwrap().email, "a.com@fastmail.com");
+        let error = results[1].as_ref().unwrap_err();
+        assert_eq!(error.index, 1);
+        assert!(matches!(error.error, FastmailError::InvalidInput(_)));
+        assert_eq!(results[2].as_ref().unwrap().email, "b.com@fastmail.com");
+    }
+
+    #[test]
+    fn test_method_error_response() {
+        let server = MockServer::start();
+        mock_method_response(
+            &server,
+            "error",
+            "get-0",
+            serde_json::json!({
+                "type": "urn:ietf:params:jmap:error:unknownCapability",
+                "description": "Unsupported capability"
+            }),
+        );
+        let client = mock_client(&server);
+        let err = client.list_masked_emails(&"u1".into()).unwrap_err();
+        assert!(matches!(err, FastmailError::Api(_)));
+        assert_eq!(
+            err.to_string(),
+            "API error: unknownCapability: Unsupported capability"
+        );
+    }
+
+    #[test]
+    fn test_request_level_error_response() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(POST).path("/jmap/api/");
+            then.status(400).json_body(serde_json::json!({
+                "type": "urn:ietf:params:jmap:error:notRequest",
+                "status": 400,
+                "detail": "Request body is not a valid JMAP request"
+            }));
+        });
+        let client = mock_client(&server);
+        let err = client.list_masked_emails(&"u1".into()).unwrap_err();
+        assert!(matches!(err, FastmailError::Api(_)));
+        assert!(err.to_string().contains("notRequest: Request body"));
+    }
+
+    #[test]
+    fn test_list_masked_emails_malformed_body() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(POST).path("/jmap/api/");
+            then.status(200).body("{not json");
+        });
+        let client = mock_client(&server);
+        assert!(matches!(client.list_masked_emails(&"u1".into()), Err(FastmailError::Parse(_))));
+    }
+
+    #[test]
+    fn test_collects_advisory_headers_once() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/jmap/session");
+            then.status(200)
+                .header("Deprecation", "true")
+                .header("X-Request-Id", "abc-123")
+                .body(SESSION_BODY);
+        });
+        let notified = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let sink = notified.clone();
+        let client = FastmailClient::builder("token")
+            .session_url(server.url("/jmap/session"))
+            .on_advisory(move |a| sink.lock().unwrap().push(a.header.clone()))
+            .build()
+            .unwrap();
+        client.refresh_session().unwrap();
+        client.refresh_session().unwrap();
+
+        let advisories = client.advisories();
+        assert_eq!(advisories.len(), 1);
+        assert_eq!(advisories[0].header, "deprecation");
+        assert_eq!(advisories[0].value, "true");
+        assert_eq!(*notified.lock().unwrap(), ["deprecation"]);
+    }
+
+    #[test]
+    fn test_records_last_request_stats() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/jmap/session");
+            then.status(200)
+                .header("X-Request-Id", "abc-123")
+                .body(SESSION_BODY);
+        });
+        let client = mock_client(&server);
+        assert!(client.last_request_stats().is_none());
+        client.get_session().unwrap();
+
+        let stats = client.last_request_stats().unwrap();
+        assert_eq!(stats.status, 200);
+        assert_eq!(stats.request_id.as_deref(), Some("abc-123"));
+    }
+
+    /// Serve a single canned HTTP response on `listener`.
+    fn serve_once(listener: std::net::TcpListener, body: &str) {
+        use std::io::{BufRead, BufReader, Write};
+
+        let (mut stream, _) = listener.accept().expect("accept failed");
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line.trim().is_empty() {
+                break;
+            }
+        }
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_retries_refused_connection() {
+        // Reserve a port, then leave it closed so the first attempt is refused
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let server = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            let listener = std::net::TcpListener::bind(("127.0.0.1", port)).unwrap();
+            serve_once(listener, SESSION_BODY)
+        });
+
+        let client = FastmailClient::builder("token")
+            .session_url(format!("http://127.0.0.1:{}/jmap/session", port))
+            .retry_delay(Duration::from_millis(400))
+            // Jittered waits can be short, so allow enough attempts to outlast the gap
+            .max_retries(5)
+            .build()
+            .unwrap();
+        let account_id = client.get_account_id();
+        server.join().unwrap();
+        assert_eq!(account_id.unwrap().as_ref(), "u123");
+    }
+
+    #[test]
+    fn test_does_not_retry_when_disabled() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let client = FastmailClient::builder("token")
+            .session_url(format!("http://127.0.0.1:{}/jmap/session", port))
+            .max_retries(0)
+            .build()
+            .unwrap();
+        assert!(matches!(client.get_session(), Err(FastmailError::Http(_))));
+    }
+
+    #[test]
+    fn test_create_timeout_returns_alias_created_anyway() {
+        let server = MockServer::start();
+        let set = server.mock(|when, then| {
+            when.method(POST).path("/jmap/api/").body_contains(r#""create""#);
+            then.status(200).delay(Duration::from_secs(2)).body("{}");
+        });
+        let untag = server.mock(|when, then| {
+            when.method(POST)
+                .path("/jmap/api/")
+                .body_contains(r#""update":{"m1":{"description":"shop"}}"#);
+            then.status(200).json_body(serde_json::json!({
+                "methodResponses": [["MaskedEmail/set", {"updated": {"m1": null}}, "set-0"]]
+            }));
+        });
+        // The server applied the timed-out create but, like Fastmail, set
+        // createdBy itself; the tag is the seeded first value
+        let tagged = format!("shop [tmail#{:016x}]", Jitter::new(7).next());
+        let list = serde_json::json!({
+            "accountId": "u1",
+            "state": "1",
+            "list": [
+                {"id": "m0", "email": "old@fastmail.com", "description": "shop"},
+                {"id": "m1", "email": "abc@fastmail.com", "description": tagged}
+            ]
+        });
+        let get = server.mock(|when, then| {
+            when.method(POST).path("/jmap/api/").body_contains("MaskedEmail/get");
+            then.status(200).json_body(serde_json::json!({
+                "methodResponses": [["MaskedEmail/get", list, "get-0"]],
+                "sessionState": "s1"
+            }));
+        });
+        let client = FastmailClient::builder("token")
+            .api_url(server.url("/jmap/api/"))
+            .timeout(Duration::from_millis(200))
+            .retry_delay(Duration::ZERO)
+            .jitter_seed(7)
+            .build()
+            .unwrap();
+        let created = client
+            .create_masked_email(&"u1".into(), Some("shop"), Some("example.com"))
+            .unwrap();
+        assert_eq!(created.id, Some("m1".into()));
+        assert_eq!(created.description.as_deref(), Some("shop"));
+        set.assert_hits(1);
+        get.assert_hits(1);
+        untag.assert_hits(1);
+    }
+
+    #[test]
+    fn test_reads_retry_after_timeout_but_writes_do_not() {
+        let server = MockServer::start();
+        let slow = |method: &'static str| {
+            server.mock(|when, then| {
+                when.method(POST).path("/jmap/api/").body_contains(method);
+                then.status(200).delay(Duration::from_secs(2)).body("{}");
+            })
+        };
+        let get = slow("MaskedEmail/get");
+        let set = slow("MaskedEmail/set");
+        let client = FastmailClient::builder("token")
+            .api_url(server.url("/jmap/api/"))
+            .timeout(Duration::from_millis(200))
+            .retry_delay(Duration::ZERO)
+            .max_retries(1)
+            .build()
+            .unwrap();
+
+        assert!(client.list_masked_emails(&"u1".into()).is_err());
+        get.assert_hits(2);
+        assert!(client.enable_masked_email(&"u1".into(), &"m1".into()).is_err());
+        set.assert_hits(1);
+
+        let mut request = JmapRequest::new();
+        request.call("MaskedEmail/query", &[MASKED_EMAIL_CAPABILITY], serde_json::json!({}));
+        request.call("MaskedEmail/get", &[MASKED_EMAIL_CAPABILITY], serde_json::json!({}));
+        assert!(request.is_idempotent());
+        request.call("MaskedEmail/set", &[MASKED_EMAIL_CAPABILITY], serde_json::json!({}));
+        assert!(!request.is_idempotent());
+    }
+
+    #[test]
+    fn test_request_uses_only_needed_capabilities() {
+        let mut request = JmapRequest::new();
+        assert_eq!(request.using, [JMAP_CORE_CAPABILITY]);
+        request.call("MaskedEmail/get", &[MASKED_EMAIL_CAPABILITY], serde_json::json!({}));
+        request.call("MaskedEmail/set", &[MASKED_EMAIL_CAPABILITY], serde_json::json!({}));
+        assert_eq!(request.using, [JMAP_CORE_CAPABILITY, MASKED_EMAIL_CAPABILITY]);
+
+        let session: SessionResponse = serde_json::from_value(serde_json::json!({
+            "capabilities": {"urn:ietf:params:jmap:core": {}},
+            "primaryAccounts": {}
+        }))
+        .unwrap();
+        assert_eq!(request.missing_capability(&session), Some(MASKED_EMAIL_CAPABILITY));
+    }
+
+    #[test]
+    fn test_missing_capability_is_named() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/jmap/session");
+            then.status(200).json_body(serde_json::json!({
+                "capabilities": {"urn:ietf:params:jmap:core": {}},
+                "primaryAccounts": {}
+            }));
+        });
+        let api = server.mock(|when, then| {
+            when.method(POST).path("/jmap/api/");
+            then.status(400).json_body(serde_json::json!({
+                "type": "urn:ietf:params:jmap:error:unknownCapability",
+                "status": 400,
+                "detail": "The request used an unsupported capability"
+            }));
+        });
+        let client = mock_client(&server);
+
+        // Named from the session when the server rejects the request
+        let err = client.list_masked_emails(&"u1".into()).unwrap_err();
+        assert!(
+            matches!(&err, FastmailError::MissingCapability(urn) if urn == MASKED_EMAIL_CAPABILITY),
+            "{:?}",
+            err
+        );
+        api.assert_hits(1);
+
+        // Caught before sending once the session is known
+        let err = client.list_masked_emails(&"u1".into()).unwrap_err();
+        assert!(matches!(err, FastmailError::MissingCapability(_)), "{:?}", err);
+        api.assert_hits(1);
+    }
+
+    #[test]
+    fn test_backoff_jitter_is_seeded_and_capped() {
+        let client = |seed| {
+            FastmailClient::builder("token")
+                .retry_delay(Duration::from_secs(1))
+                .jitter_seed(seed)
+                .build()
+                .unwrap()
+        };
+        let (a, b) = (client(42), client(42));
+        let delays: Vec<Duration> = (0..8).map(|attempt| a.backoff(attempt)).collect();
+        let same_seed: Vec<Duration> = (0..8).map(|attempt| b.backoff(attempt)).collect();
+        assert_eq!(delays, same_seed);
+        assert!(delays[0] <= Duration::from_secs(1));
+        assert!(delays.iter().all(|d| *d <= MAX_RETRY_DELAY));
+        // Not a fixed schedule
+        assert!(delays.windows(2).any(|w| w[0] != w[1]));
+    }
+
+    #[test]
+    fn test_shared_http_client() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/jmap/session");
+            then.status(200).body(SESSION_BODY);
+        });
+        let http = reqwest::blocking::Client::new();
+        let clients: Vec<FastmailClient> = (0..2)
+            .map(|_| {
+                FastmailClient::builder("token")
+                    .with_http_client(http.clone())
+                    .session_url(server.url("/jmap/session"))
+                    .build()
+                    .unwrap()
+            })
+            .collect();
+        for client in &clients {
+            assert_eq!(client.get_account_id().unwrap().as_ref(), "u123");
+        }
+    }
+
+    #[test]
+    fn test_min_interval_spaces_requests() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(GET).path("/jmap/session");
+            then.status(200).body(SESSION_BODY);
+        });
+        let client = FastmailClient::builder("token")
+            .session_url(server.url("/jmap/session"))
+            .min_interval(Duration::from_millis(200))
+            .build()
+            .unwrap();
+
+        let started = Instant::now();
+        client.refresh_session().unwrap();
+        client.refresh_session().unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        mock.assert_hits(2);
+    }
+
+    #[test]
+    fn test_response_for_matches_method_and_call_id() {
+        let jmap: JmapResponse = serde_json::from_value(serde_json::json!({
+            "methodResponses": [
+                ["MaskedEmail/query", {"ids": ["m1"]}, "q"],
+                ["MaskedEmail/get", {"list": []}, "g"]
+            ]
+        }))
+        .unwrap();
+        let get = jmap.response_for("MaskedEmail/get", "g").unwrap();
+        assert_eq!(get["list"], serde_json::json!([]));
+        assert!(jmap.response_for("MaskedEmail/get", "q").is_none());
+        assert!(jmap.response_for("MaskedEmail/set", "g").is_none());
+    }
+
+    #[test]
+    fn test_normalize_domain_trims_and_lowercases() {
+        assert_eq!(
+            normalize_domain("  Example.COM ").unwrap().as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(normalize_domain("   ").unwrap(), None);
+    }
+
+    #[test]
+    fn test_normalize_domain_rejects_url() {
+        let err = normalize_domain("https://example.com/signup").unwrap_err();
+        assert!(matches!(err, FastmailError::InvalidInput(_)));
+        assert!(err.to_string().contains("'example.com'"));
+
+        let err = normalize_domain("example.com/path").unwrap_err();
+        assert!(err.to_string().contains("'example.com'"));
+    }
+
+    #[test]
+    fn test_domain_from_url() {
+        assert_eq!(
+            domain_from_url("https://Shop.Example.com:8443/signup?ref=1").as_deref(),
+            Some("shop.example.com")
+        );
+        assert_eq!(domain_from_url("example.com"), None);
+    }
+
+    #[test]
+    fn test_sort_newest_first() {
+        let email = |address: &str, created_at: Option<&str>| MaskedEmail {
+            id: None,
+            email: address.to_string(),
+            state: None,
+            for_domain: None,
+            description: None,
+            created_at: created_at.map(str::to_string),
+            last_message_at: None,
+            url: None,
+            created_by: None,
+        };
+        let mut emails = vec![
+            email("c@fastmail.com", None),
+            email("b@fastmail.com", Some("2024-01-01T00:00:00Z")),
+            email("d@fastmail.com", Some("2024-06-01T00:00:00Z")),
+            email("a@fastmail.com", Some("2024-01-01T00:00:00Z")),
+            email("e@fastmail.com", Some("2024-06-01T00:00:00.5Z")),
+            email("f@fastmail.com", Some("garbage")),
+        ];
+        sort_newest_first(&mut emails);
+        let order: Vec<&str> = emails.iter().map(|e| e.email.as_str()).collect();
+        assert_eq!(
+            order,
+            [
+                "e@fastmail.com",
+                "d@fastmail.com",
+                "a@fastmail.com",
+                "b@fastmail.com",
+                "c@fastmail.com",
+                "f@fastmail.com"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_date_parse_and_order() {
+        let date = |s: &str| s.parse::<Date>();
+        assert!(date("2024-02-29").is_ok());
+        assert!(date("2023-02-29").is_err());
+        assert!(date("2024-13-01").is_err());
+        assert!(date("2024-1-01").is_err());
+        assert!(date("yesterday").is_err());
+        assert!(date("2021-12-31").unwrap() < date("2022-01-01").unwrap());
+        assert_eq!(date("2022-01-05").unwrap().to_string(), "2022-01-05");
+
+        let mut email: MaskedEmail =
+            serde_json::from_value(serde_json::json!({"email": "abc@fastmail.com"})).unwrap();
+        assert_eq!(email.created_date(), None);
+        email.created_at = Some("2024-01-15T10:30:00Z".to_string());
+        assert_eq!(email.created_date(), date("2024-01-15").ok());
+        email.created_at = Some("garbage".to_string());
+        assert_eq!(email.created_date(), None);
+    }
+
+    #[test]
+    fn test_timestamp_parse_and_order() {
+        let time = |s: &str| s.parse::<Timestamp>();
+        assert!(time("2024-01-15T10:30:00Z").is_ok());
+        assert!(time("2024-01-15T23:59:60.123456789123Z").is_ok());
+        assert!(time("2024-01-15T10:30:00").is_err());
+        assert!(time("2024-01-15T24:00:00Z").is_err());
+        assert!(time("2024-01-15T10:30:00.Z").is_err());
+        assert!(time("2024-01-15T10:30:+0Z").is_err());
+        assert!(time("2024-02-30T10:30:00Z").is_err());
+        assert!(time("2024-01-15").is_err());
+        // Fractions count, however many digits they have
+        assert!(time("2024-01-15T10:30:00Z").unwrap() < time("2024-01-15T10:30:00.5Z").unwrap());
+        assert_eq!(time("2024-01-15T10:30:00.5Z").ok(), time("2024-01-15T10:30:00.500Z").ok());
+        assert!(time("2024-01-15T23:59:59.9Z").unwrap() < time("2024-01-16T00:00:00Z").unwrap());
+    }
+
+    #[test]
+    fn test_date_arithmetic() {
+        let date = |s: &str| s.parse::<Date>().unwrap();
+        assert_eq!(Date::from_days(0), date("1970-01-01"));
+        assert_eq!(date("2024-03-01").to_days(), 19_783);
+        assert_eq!(date("2024-03-01").days_before(1), date("2024-02-29"));
+        assert_eq!(date("2023-01-10").days_before(365), date("2022-01-10"));
+        assert!(Date::today() > date("2024-01-01"));
+    }
+
+    #[test]
+    fn test_last_active_date() {
+        let mut email: MaskedEmail = serde_json::from_value(serde_json::json!({
+            "email": "abc@fastmail.com",
+            "createdAt": "2020-05-01T00:00:00Z"
+        }))
+        .unwrap();
+        // Never used: judged by when it was created
+        assert_eq!(email.last_active_date(), "2020-05-01".parse().ok());
+        email.last_message_at = Some("2023-07-04T12:00:00Z".to_string());
+        assert_eq!(email.last_active_date(), "2023-07-04".parse().ok());
+        email.last_message_at = Some("bogus".to_string());
+        assert_eq!(email.last_active_date(), None);
+    }
+
+    #[test]
+    fn test_create_masked_emails_reports_failed_spec() {
+        let server = MockServer::start();
+        mock_method_response(
+            &server,
+            "MaskedEmail/set",
+            "set-0",
+            serde_json::json!({
+                "accountId": "u1",
+                "created": {"c0": {"id": "m1", "email": "abc@fastmail.com"}},
+                "notCreated": {"c2": {"type": "overQuota", "description": "Too many"}}
+            }),
+        );
+        let spec = |description: &str, domain: &str| CreateSpec {
+            description: Some(description.to_string()),
+            for_domain: Some(domain.to_string()),
+            ..Default::default()
+        };
+        let specs = [spec("a", "a.com"), spec("b", "not a domain"), spec("c", "c.com")];
+        let client = mock_client(&server);
+        let results = client.create_masked_emails(&"u1".into(), &specs).unwrap();
+        assert!(results[0].is_ok());
+
+        let invalid = results[1].as_ref().unwrap_err();
+        assert_eq!((invalid.index, invalid.code.as_deref()), (1, None));
+        assert!(matches!(invalid.error, FastmailError::InvalidInput(_)));
+
+        let rejected = results[2].as_ref().unwrap_err();
+        assert_eq!(rejected.index, 2);
+        assert_eq!(rejected.spec.description.as_deref(), Some("c"));
+        assert_eq!(rejected.code.as_deref(), Some("overQuota"));
+        assert_eq!(rejected.to_string(), "entry 2 failed: API error: overQuota: Too many");
+    }
+
+    #[test]
+    fn test_set_masked_email_states_sends_one_update() {
+        let server = MockServer::start();
+        let set = server.mock(|when, then| {
+            when.method(POST).path("/jmap/api/").json_body_partial(
+                r#"{"methodCalls": [["MaskedEmail/set", {"update": {
+                    "m1": {"state": "disabled"}, "m2": {"state": "disabled"}
+                }}, "set-0"]]}"#,
+            );
+            then.status(200).json_body(serde_json::json!({
+                "methodResponses": [
+                    ["MaskedEmail/set", {"updated": {"m1": null, "m2": null}}, "set-0"]
+                ],
+                "sessionState": "s1"
+            }));
+        });
+        let client = mock_client(&server);
+        let results = client
+            .set_masked_email_states(&"u1".into(), &["m1".into(), "m2".into()], "disabled")
+            .unwrap();
+        assert!(results.iter().all(Result::is_ok));
+        set.assert_hits(1);
+    }
+
+    #[test]
+    fn test_destroy_masked_emails_batch() {
+        let server = MockServer::start();
+        mock_method_response(
+            &server,
+            "MaskedEmail/set",
+            "set-0",
+            serde_json::json!({
+                "accountId": "u1",
+                "updated": {"m1": null},
+                "notUpdated": {"m2": {"type": "notFound"}}
+            }),
+        );
+        let client = mock_client(&server);
+        let results = client
+            .destroy_masked_emails(&"u1".into(), &["m1".into(), "m2".into()])
+            .unwrap();
+        assert!(results[0].is_ok());
+        assert!(matches!(&results[1], Err(FastmailError::Api(m)) if m == "notFound"));
+    }
+
+    #[test]
+    fn test_domain_matches() {
+        assert!(domain_matches("example.com", "example.com", false));
+        assert!(domain_matches("Example.COM.", "example.com", false));
+        assert!(!domain_matches("mail.example.com", "example.com", false));
+        assert!(domain_matches("mail.example.com", "example.com", true));
+        assert!(domain_matches("a.b.example.com", "example.com", true));
+        assert!(domain_matches("example.com", "example.com", true));
+        // A shared suffix isn't a subdomain
+        assert!(!domain_matches("notexample.com", "example.com", true));
+        assert!(!domain_matches("example.com.evil.net", "example.com", true));
+        assert!(!domain_matches("example.com", "", true));
+    }
+
+    #[test]
+    fn test_registrable_domain() {
+        assert_eq!(registrable_domain("mail.Example.com").as_deref(), Some("example.com"));
+        assert_eq!(registrable_domain("www.amazon.co.uk").as_deref(), Some("amazon.co.uk"));
+        assert_eq!(registrable_domain("example.com").as_deref(), Some("example.com"));
+        assert_eq!(registrable_domain("localhost").as_deref(), Some("localhost"));
+        assert_eq!(registrable_domain(""), None);
+    }
+
+    #[test]
+    fn test_clean_token() {
+        assert_eq!(clean_token("  fmu1-abc123\n"), "fmu1-abc123");
+        assert_eq!(clean_token("Bearer fmu1-abc123"), "fmu1-abc123");
+        assert_eq!(clean_token(" bearer   fmu1-abc123 "), "fmu1-abc123");
+        assert_eq!(clean_token("Bearer"), "Bearer");
+        assert_eq!(clean_token("   "), "");
+
+        assert!(token_looks_valid("fmu1-0123abcd-0123456789abcdef"));
+        assert!(!token_looks_valid("fmu1-0123abcd 0123456789abcdef"));
+        assert!(!token_looks_valid("password123"));
+    }
+
+    #[test]
+    fn test_builder_cleans_token() {
+        let server = MockServer::start();
+        let session = server.mock(|when, then| {
+            when.method(GET)
+                .path("/jmap/session")
+                .header("authorization", "Bearer fmu1-abc");
+            then.status(200).body(SESSION_BODY);
+        });
+        let client = FastmailClient::builder(" Bearer fmu1-abc\n")
+            .session_url(server.url("/jmap/session"))
+            .build()
+            .unwrap();
+        client.get_session().unwrap();
+        session.assert();
+    }
+
+    #[test]
+    fn test_masked_email_display() {
+        let mut email: MaskedEmail = serde_json::from_value(serde_json::json!({
+            "email": "abc@fastmail.com",
+            "forDomain": "example.com",
+            "description": "newsletter",
+            "state": "enabled"
+        }))
+        .unwrap();
+        assert_eq!(
+            email.to_string(),
+            "abc@fastmail.com (example.com) — newsletter [enabled]"
+        );
+        assert_eq!(email.summary_line(), "abc@fastmail.com (example.com) — newsletter");
+
+        email.for_domain = Some(String::new());
+        email.description = None;
+        email.state = None;
+        assert_eq!(email.to_string(), "abc@fastmail.com");
+    }
+
+    #[test]
+    fn test_update_patch() {
+        let patch = update_patch(
+            UpdateSpec::Set("shop".to_string()),
+            UpdateSpec::Keep,
+            UpdateSpec::Clear,
+        )
+        .unwrap();
+        assert_eq!(patch, serde_json::json!({"description": "shop", "url": null}));
+
+        let patch = update_patch(
+            UpdateSpec::Keep,
+            UpdateSpec::Set(" Example.COM ".to_string()),
+            UpdateSpec::Keep,
+        )
+        .unwrap();
+        assert_eq!(patch, serde_json::json!({"forDomain": "example.com"}));
+
+        let patch =
+            update_patch(UpdateSpec::Clear, UpdateSpec::Set("  ".to_string()), UpdateSpec::Keep)
+                .unwrap();
+        assert_eq!(patch, serde_json::json!({"description": null, "forDomain": null}));
+    }
+
+    #[test]
+    fn test_prefix_from_domain() {
+        assert_eq!(prefix_from_domain("amazon.com").as_deref(), Some("amazon"));
+        assert_eq!(prefix_from_domain("www.amazon.co.uk").as_deref(), Some("amazon"));
+        assert_eq!(prefix_from_domain("my-shop.example.org").as_deref(), Some("example"));
+        assert_eq!(prefix_from_domain("my-shop.io").as_deref(), Some("my_shop"));
+        assert_eq!(prefix_from_domain(""), None);
+    }
+
+    #[test]
+    fn test_validate_email_prefix() {
+        assert!(validate_email_prefix("news_2024").is_ok());
+        assert!(matches!(
+            validate_email_prefix("News!"),
+            Err(FastmailError::InvalidInput(_))
+        ));
+        assert!(validate_email_prefix(&"a".repeat(65)).is_err());
+        assert!(validate_email_prefix("").is_err());
+    }
+
+    #[test]
+    fn test_description_tags() {
+        assert_eq!(description_tags("shop #Work and #bills #"), ["Work", "bills"]);
+        assert_eq!(strip_tags("shop #work  and #bills #"), "shop and #");
+        assert!(description_tags("issue#42").is_empty());
+
+        let email: MaskedEmail = serde_json::from_value(serde_json::json!({
+            "email": "abc@fastmail.com",
+            "description": "newsletter #work"
+        }))
+        .unwrap();
+        assert!(email.has_tag("WORK"));
+        assert!(!email.has_tag("wor"));
+
+        assert_eq!(parse_tag("#work").unwrap(), "work");
+        assert!(parse_tag("#").is_err());
+        assert!(parse_tag("two words").is_err());
+    }
+
+    #[test]
+    fn test_error_is_send_sync() {
+        fn assert_send_sync<T: Send + Sync + 'static>() {}
+        assert_send_sync::<FastmailError>();
+
+        let boxed: Box<dyn std::error::Error + Send + Sync> =
+            FastmailError::NotFound("masked email 'a@b.c'".into()).into();
+        assert_eq!(boxed.to_string(), "Not found: masked email 'a@b.c'");
+        let moved = std::thread::spawn(move || boxed.to_string()).join().unwrap();
+        assert!(moved.starts_with("Not found"));
+    }
+
+    #[test]
+    fn test_builder_rejects_malformed_proxy() {
+        let result = FastmailClient::builder("token").proxy("not a url").build();
+        assert!(matches!(result, Err(FastmailError::Build(_))));
+    }
+
+    #[test]
+    fn test_builder_accepts_proxy() {
+        let result = FastmailClient::builder("token")
+            .proxy("socks5://127.0.0.1:1080")
+            .build();
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_builder_rejects_malformed_certificate() {
+        let result = FastmailClient::builder("token")
+            .add_root_certificate("-----BEGIN CERTIFICATE-----\nnot base64\n")
+            .build();
+        assert!(matches!(result, Err(FastmailError::Build(_))), "{:?}", result.err());
+    }
+
+    #[test]
+    fn test_sends_configured_user_agent() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(GET)
+                .path("/jmap/session")
+                .header("user-agent", "tmail-test/1.0");
+            then.status(200).body(SESSION_BODY);
+        });
+        let client = FastmailClient::builder("token")
+            .session_url(server.url("/jmap/session"))
+            .user_agent("tmail-test/1.0")
+            .build()
+            .unwrap();
+        client.get_session().unwrap();
+        mock.assert();
+    }
+
+    #[test]
+    #[ignore] // Run with: cargo test -- --ignored
+    fn test_get_session() {
+        let client = FastmailClient::new(get_test_token());
+        let result = client.get_session();
+        println!("Session result: {:#?}", result);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    #[ignore]
+    fn test_get_account_id() {
+        let client = FastmailClient::new(get_test_token());
+        let result = client.get_account_id();
+        println!("Account ID result: {:#?}", result);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    #[ignore]
+    fn test_capabilities() {
+        let client = FastmailClient::new(get_test_token());
+        let result = client.capabilities();
+        println!("Capabilities result: {:#?}", result);
+        let capabilities = result.expect("Failed to get capabilities");
+        assert!(capabilities.contains_key(JMAP_CORE_CAPABILITY));
+        assert!(capabilities.contains_key(MASKED_EMAIL_CAPABILITY));
+    }
+
+    #[test]
+    #[ignore]
+    fn test_create_masked_email() {
+        let client = FastmailClient::new(get_test_token());
+        let account_id = client.get_account_id().expect("Failed to get account ID");
+        let result = client.create_masked_email(&account_id, Some("test from tmail"), None);
+        println!("Create masked email result: {:#?}", result);
+        assert!(result.is_ok());
+
+        // Cleanup
+        let created = result.unwrap();
+        let id = created.id.expect("Created email has no ID");
+        client.destroy_masked_email(&account_id, &id).expect("Failed to cleanup");
+    }
+
+    #[test]
+    #[ignore]
+    fn test_create_masked_emails() {
+        let client = FastmailClient::new(get_test_token());
+        let account_id = client.get_account_id().expect("Failed to get account ID");
+        let specs = vec![
+            CreateSpec {
+                description: Some("test batch 1".to_string()),
+                ..Default::default()
+            },
+            CreateSpec {
+                description: Some("test batch 2".to_string()),
+                for_domain: Some("example.com".to_string()),
+                url: Some("https://example.com/signup".to_string()),
+                email_prefix: None,
+                state: None,
+            },
+        ];
+        let results = client
+            .create_masked_emails(&account_id, &specs)
+            .expect("Failed to create batch");
+        println!("Batch create results: {:#?}", results);
+        assert_eq!(results.len(), 2);
+
+        // Cleanup
+        for created in results {
+            let id = created.expect("Batch entry failed").id.expect("Created email has no ID");
+            client.destroy_masked_email(&account_id, &id).expect("Failed to cleanup");
+        }
+    }
+
+    #[test]
     #[ignore]
     fn test_list_masked_emails() {
         let client = FastmailClient::new(get_test_token());
         let account_id = client.get_account_id().expect("Failed to get account ID");
         let result = client.list_masked_emails(&account_id);
         println!("List masked emails result: {:#?}", result);
+        // A brand-new account legitimately has no masked emails
         assert!(result.is_ok());
-        let emails = result.unwrap();
-        assert!(!emails.is_empty());
+    }
+
+    #[test]
+    #[ignore]
+    fn test_update_masked_email() {
+        let client = FastmailClient::new(get_test_token());
+        let account_id = client.get_account_id().expect("Failed to get account ID");
+        let created = client
+            .create_masked_email(&account_id, Some("test update"), None)
+            .expect("Failed to create test email");
+        let id = created.id.expect("Created email has no ID");
+
+        let result = client.update_masked_email(
+            &account_id,
+            &id,
+            UpdateSpec::Set("test updated".to_string()),
+            UpdateSpec::Set("example.com".to_string()),
+            UpdateSpec::Keep,
+        );
+        println!("Update result: {:#?}", result);
+        assert!(result.is_ok());
+
+        let emails = client.list_masked_emails(&account_id).expect("Failed to list");
+        let updated = emails.iter().find(|e| e.id.as_ref() == Some(&id)).unwrap();
+        assert_eq!(updated.description.as_deref(), Some("test updated"));
+        assert_eq!(updated.for_domain.as_deref(), Some("example.com"));
+
+        // Cleanup
+        client.destroy_masked_email(&account_id, &id).expect("Failed to cleanup");
     }
 
     #[test]
@@ -381,7 +3990,7 @@ mod tests {
 
         // Create a test email first
         let created = client
-            .create_masked_email(&account_id, Some("test delete"))
+            .create_masked_email(&account_id, Some("test delete"), None)
             .expect("Failed to create test email");
         println!("Created test email: {:#?}", created);
 
@@ -394,7 +4003,7 @@ mod tests {
 
         // Verify it's now disabled
         let emails = client.list_masked_emails(&account_id).expect("Failed to list");
-        let archived = emails.iter().find(|e| e.id.as_deref() == Some(&id));
+        let archived = emails.iter().find(|e| e.id.as_ref() == Some(&id));
         assert!(archived.is_some());
         assert_eq!(archived.unwrap().state.as_deref(), Some("disabled"));
 
@@ -1,17 +1,61 @@
+mod backup;
+mod cache;
+mod config;
+mod output;
 mod prompt;
 
-use clap::{Parser, Subcommand};
-use serde::{Deserialize, Serialize};
-use std::fs;
+/// Commit and compiler details captured by `build.rs`.
+mod build_info {
+    include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
+}
+
+use clap::{Args, Parser, Subcommand};
+use config::{
+    cache_path, config_path, load_config, update_config, Config, ConfigError, HttpConfig,
+};
+use std::collections::HashSet;
 use std::io::{self, Write};
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
-use tmail::{FastmailClient, MaskedEmail};
-use serde_json;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tmail::{
+    AccountId, BatchItemError, CreateSpec, Date, FastmailClient, FastmailError, ListResult,
+    MaskedEmail, MaskedEmailId, MaskedEmailStore, UpdateSpec,
+};
+
+const EXIT_USAGE: i32 = 1;
+const EXIT_AUTH: i32 = 2;
+const EXIT_NOT_FOUND: i32 = 3;
+const EXIT_API: i32 = 4;
+
+/// Warn after creating when this close to the masked email limit.
+const QUOTA_WARNING_MARGIN: u64 = 10;
+
+/// The masked email list in Fastmail's settings. There's no documented
+/// page for a single alias, so `open` points out which entry to look for.
+const MASKED_EMAIL_SETTINGS_URL: &str = "https://app.fastmail.com/settings/security/maskedemail";
 
 #[derive(Parser)]
 #[command(name = "tmail")]
 #[command(about = "CLI for interacting with email APIs")]
 struct Cli {
+    /// Suppress non-essential output
+    #[arg(short, long, global = true)]
+    quiet: bool,
+    /// Disable colored output (also set by NO_COLOR)
+    #[arg(long, global = true)]
+    no_color: bool,
+    /// API token to use instead of FASTMAIL_TOKEN or the saved config
+    #[arg(long, global = true, value_name = "TOKEN")]
+    token: Option<String>,
+    /// Account to act on instead of the saved one; skips the session lookup
+    /// when a token is given
+    #[arg(long, global = true, value_name = "ID")]
+    account_id: Option<String>,
+    /// Config file to read and save instead of the default one
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -20,6 +64,17 @@ struct Cli {
 enum Commands {
     /// Authenticate with Fastmail API
     Login,
+    /// Show which account the saved token belongs to
+    Whoami,
+    /// Show version and build details for bug reports
+    Version,
+    /// Check the config, token, network and clock, with hints for fixing them
+    Doctor,
+    /// Inspect the config file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
     /// Manage masked emails
     Masked {
         #[command(subcommand)]
@@ -27,56 +82,561 @@ enum Commands {
     },
 }
 
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// Show the config file, account and effective HTTP settings
+    Show,
+}
+
 #[derive(Subcommand)]
 enum MaskedCommands {
     /// List all masked emails
-    List {
-        /// Show all emails including disabled/deleted
+    List(ListArgs),
+    /// Create a new masked email
+    Create(CreateArgs),
+    /// Count masked emails that aren't deleted
+    Count {
+        /// Show usage against the account's masked email limit
+        #[arg(long)]
+        quota: bool,
+    },
+    /// Find masked emails by description or domain
+    Search(SearchArgs),
+    /// Summarize usage: totals, how many got mail, oldest/newest and top domains
+    Report {
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Delete (archive) a masked email
+    Delete {
+        /// The email address to archive (e.g., abc123@fastmail.com)
+        email: Option<String>,
+    },
+    /// Disable every masked email for a domain, e.g. after a breach
+    Disable {
+        /// Disable the aliases whose domain is exactly this
+        #[arg(long, value_name = "DOMAIN")]
+        domain: String,
+        /// Skip the confirmation prompt
+        #[arg(short, long)]
+        yes: bool,
+        #[command(flatten)]
+        bulk: BulkArgs,
+    },
+    /// Re-enable every disabled masked email for a domain
+    Enable {
+        /// Enable the aliases whose domain is exactly this
+        #[arg(long, value_name = "DOMAIN")]
+        domain: String,
+        /// Skip the confirmation prompt
         #[arg(short, long)]
-        all: bool,
+        yes: bool,
+        #[command(flatten)]
+        bulk: BulkArgs,
+    },
+    /// Permanently delete one or more masked emails
+    Destroy {
+        /// The masked email addresses to destroy
+        #[arg(required = true)]
+        emails: Vec<String>,
+        /// Skip the confirmation prompt
+        #[arg(short, long)]
+        yes: bool,
+        #[command(flatten)]
+        bulk: BulkArgs,
+    },
+    /// Destroy masked emails that haven't received mail in a while
+    Prune {
+        /// Prune aliases with no mail in this many days (never-used ones count
+        /// from when they were created)
+        #[arg(long, value_name = "DAYS")]
+        older_than: u32,
+        /// Only prune aliases that have never received mail
+        #[arg(long)]
+        unused: bool,
+        /// Skip the confirmation prompt
+        #[arg(short, long)]
+        yes: bool,
+        #[command(flatten)]
+        bulk: BulkArgs,
+    },
+    /// Show every field of a masked email
+    Info {
+        /// The masked email address to show
+        email: String,
         /// Output as JSON
         #[arg(long)]
         json: bool,
     },
-    /// Create a new masked email
-    Create {
-        /// Description for the masked email
+    /// Switch a masked email between enabled and disabled
+    Toggle {
+        /// The masked email address to toggle
+        email: String,
+    },
+    /// Send a test message to a masked email to check that it forwards
+    /// (needs a token with the Email submission scope)
+    Test {
+        /// The masked email address to send to
+        email: String,
+        /// Wait for the message to arrive
+        #[arg(long)]
+        wait: bool,
+    },
+    /// Replace a masked email with a new one for the same site, then
+    /// archive (or destroy) the old one
+    Rotate {
+        /// The masked email address to replace
+        email: String,
+        /// Archive the old address so it can be restored later (the default)
+        #[arg(long, conflicts_with = "destroy")]
+        archive: bool,
+        /// Permanently destroy the old address instead of archiving it
+        #[arg(long)]
+        destroy: bool,
+        /// Don't ask before destroying
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Edit a masked email's description or domain
+    Edit {
+        /// The masked email address to edit
+        email: String,
+        /// New description
         #[arg(short, long)]
         description: Option<String>,
-        /// Website/domain this email is for
+        /// Remove the description
+        #[arg(long, conflicts_with = "description")]
+        clear_description: bool,
+        /// New website/domain
         #[arg(short, long)]
         website: Option<String>,
     },
-    /// Delete (archive) a masked email
-    Delete {
-        /// The email address to archive (e.g., abc123@fastmail.com)
-        email: Option<String>,
+    /// Open the masked email settings page and say which entry to look for
+    Open {
+        /// The masked email address to open
+        email: String,
+        /// Print the URL instead of launching a browser
+        #[arg(long, alias = "no-browser")]
+        print_url: bool,
+    },
+    /// Recreate masked emails from a JSON or CSV backup
+    Import {
+        /// Backup file (`list --json` output, or CSV with description/forDomain columns)
+        file: PathBuf,
+        #[command(flatten)]
+        bulk: BulkArgs,
     },
 }
 
-#[derive(Serialize, Deserialize)]
-struct Config {
-    api_token: String,
-    account_id: String,
+/// How a bulk command handles a failed item.
+#[derive(Args)]
+struct BulkArgs {
+    /// Stop at the first failure, leaving the remaining items untouched
+    #[arg(long)]
+    fail_fast: bool,
+    /// Process every item, then report all failures (the default)
+    #[arg(long = "continue", conflicts_with = "fail_fast")]
+    keep_going: bool,
+    /// List what would change and exit without changing anything
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Args)]
+struct SearchArgs {
+    /// Text to look for (case-insensitive)
+    term: String,
+    /// Treat the term as a regular expression
+    #[arg(long)]
+    regex: bool,
+    /// Only match emails whose description has this #tag
+    #[arg(long, value_name = "TAG", value_parser = parse_tag)]
+    tag: Option<String>,
+    /// Show #tags in their own column instead of in the description
+    #[arg(long)]
+    show_tags: bool,
+    /// Output as JSON
+    #[arg(long)]
+    json: bool,
+    /// Print only the number of matches
+    #[arg(long)]
+    count_only: bool,
+}
+
+#[derive(Args)]
+struct ListArgs {
+    /// Show all emails including disabled/deleted
+    #[arg(short, long)]
+    all: bool,
+    /// Show enabled emails (the default when no state flag is given)
+    #[arg(long)]
+    enabled: bool,
+    /// Show disabled (archived) emails
+    #[arg(long)]
+    disabled: bool,
+    /// Show deleted emails
+    #[arg(long)]
+    deleted: bool,
+    /// Output as JSON
+    #[arg(long)]
+    json: bool,
+    /// Output one JSON object per line (NDJSON), streamed as pages arrive
+    #[arg(
+        long,
+        conflicts_with_all = ["json", "addresses_only", "group_by_domain", "format", "porcelain"]
+    )]
+    json_lines: bool,
+    /// Print only the addresses, one per line
+    #[arg(long, conflicts_with_all = ["json", "group_by_domain"])]
+    addresses_only: bool,
+    /// Show at most N entries
+    #[arg(long, value_name = "N")]
+    limit: Option<usize>,
+    /// Fetch only page P (from 1) of --limit entries from the server and show
+    /// the total, instead of fetching everything
+    #[arg(
+        long,
+        value_name = "P",
+        requires = "limit",
+        conflicts_with_all = [
+            "offline",
+            "created_by",
+            "domain",
+            "tag",
+            "since",
+            "until",
+            "sort",
+            "reverse",
+            "count_only"
+        ]
+    )]
+    page: Option<NonZeroUsize>,
+    /// Show the last fetched list without contacting the server
+    #[arg(long)]
+    offline: bool,
+    /// Only show emails whose creating client, as the server recorded it,
+    /// contains this text
+    #[arg(long, value_name = "FILTER")]
+    created_by: Option<String>,
+    /// Print aliases under a header for each domain
+    #[arg(long)]
+    group_by_domain: bool,
+    /// Only show emails for this domain
+    #[arg(long, value_name = "DOMAIN")]
+    domain: Option<String>,
+    /// Only show emails whose description has this #tag
+    #[arg(long, value_name = "TAG", value_parser = parse_tag)]
+    tag: Option<String>,
+    /// Show #tags in their own column instead of in the description
+    #[arg(long)]
+    show_tags: bool,
+    /// Let --domain match subdomains too, and group subdomains under their
+    /// site with --group-by-domain
+    #[arg(long)]
+    match_subdomains: bool,
+    /// Only show emails created on or after this date (YYYY-MM-DD)
+    #[arg(long, value_name = "DATE", value_parser = parse_date)]
+    since: Option<Date>,
+    /// Only show emails created on or before this date (YYYY-MM-DD)
+    #[arg(long, value_name = "DATE", value_parser = parse_date)]
+    until: Option<Date>,
+    /// Print each entry with a template, e.g. `{email},{domain}`. Placeholders:
+    /// {email}, {description}, {domain}, {state}, {created}, {last_message}
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        conflicts_with_all = ["json", "addresses_only", "group_by_domain"]
+    )]
+    format: Option<output::Template>,
+    /// Sort by comma-separated keys, e.g. `domain,created`. Keys: email,
+    /// description, domain, state, created, last_message
+    #[arg(long, value_name = "KEYS")]
+    sort: Option<output::SortOrder>,
+    /// Reverse the order (newest first by default)
+    #[arg(long)]
+    reverse: bool,
+    /// Stable tab-separated output for scripts; see the README for the fields
+    #[arg(
+        long,
+        conflicts_with_all = ["json", "addresses_only", "group_by_domain", "format"]
+    )]
+    porcelain: bool,
+    /// Print only the number of matching emails (ignores --limit)
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "addresses_only",
+            "group_by_domain",
+            "format",
+            "porcelain",
+            "json_lines"
+        ]
+    )]
+    count_only: bool,
+    /// Write to a file instead of stdout (`-` for stdout)
+    #[arg(short, long, value_name = "PATH")]
+    output: Option<PathBuf>,
+}
+
+fn parse_date(s: &str) -> Result<Date, String> {
+    s.parse().map_err(|_: FastmailError| "expected a valid YYYY-MM-DD date".to_string())
+}
+
+/// The message of a validation error, for clap to show after the value.
+fn input_error(error: FastmailError) -> String {
+    match error {
+        FastmailError::InvalidInput(e) => e,
+        e => e.to_string(),
+    }
+}
+
+fn parse_create_state(s: &str) -> Result<String, String> {
+    tmail::validate_create_state(s).map(|()| s.to_string()).map_err(input_error)
+}
+
+fn parse_tag(s: &str) -> Result<String, String> {
+    tmail::parse_tag(s).map_err(input_error)
+}
+
+impl ListArgs {
+    /// States selected by the flags, or `None` for every state.
+    fn states(&self) -> Option<HashSet<&'static str>> {
+        if self.all {
+            return None;
+        }
+        let mut states = HashSet::new();
+        if self.enabled {
+            states.insert("enabled");
+        }
+        if self.disabled {
+            states.insert("disabled");
+        }
+        if self.deleted {
+            states.insert("deleted");
+        }
+        if states.is_empty() {
+            states.insert("enabled");
+        }
+        Some(states)
+    }
+}
+
+#[derive(Args)]
+struct CreateArgs {
+    /// Description for the masked email (`-` reads it from stdin)
+    #[arg(short, long)]
+    description: Option<String>,
+    /// Read the description from a file
+    #[arg(long, value_name = "PATH", conflicts_with = "description")]
+    description_file: Option<PathBuf>,
+    /// Deliberately create the masked email without a description
+    #[arg(long, conflicts_with_all = ["description", "description_file"])]
+    no_description: bool,
+    /// Website/domain this email is for (a full URL also sets --url)
+    #[arg(short, long)]
+    website: Option<String>,
+    /// Exact page this email is for, e.g. a signup form
+    #[arg(long)]
+    url: Option<String>,
+    /// Start of the address (a-z, 0-9, _); defaults to the site name, e.g. `amazon`
+    #[arg(long)]
+    prefix: Option<String>,
+    /// Initial state: pending, enabled or disabled
+    #[arg(long, default_value = "enabled", value_parser = parse_create_state)]
+    state: String,
+    /// Wait until the new masked email is enabled before printing it
+    #[arg(long)]
+    wait: bool,
+    /// Append #TAG to the description; repeat for several tags
+    #[arg(long, value_name = "TAG", value_parser = parse_tag)]
+    tag: Vec<String>,
+    /// Reuse an enabled masked email with the same description instead of creating one
+    #[arg(long)]
+    if_not_exists: bool,
+    /// Warn about existing aliases for the same domain (always on when interactive)
+    #[arg(long)]
+    warn_duplicates: bool,
+    /// Output the created masked email (including its id) as JSON
+    #[arg(long)]
+    json: bool,
+    /// Print `export TMAIL_EMAIL=... TMAIL_ID=...` lines for `eval`
+    #[arg(long, conflicts_with = "json")]
+    shell: bool,
+    /// Print a `mailto:` link instead of the bare address
+    #[arg(long, conflicts_with_all = ["json", "shell"])]
+    mailto: bool,
+    /// Also open the `mailto:` link in the default mail client
+    #[arg(long, requires = "mailto")]
+    open: bool,
+}
+
+/// How `create` prints the masked email it made or reused.
+#[derive(Clone, Copy)]
+enum CreatedFormat {
+    Address,
+    Json,
+    Shell,
+    Mailto { open: bool },
+}
+
+impl CreateArgs {
+    fn created_format(&self) -> CreatedFormat {
+        if self.json {
+            CreatedFormat::Json
+        } else if self.shell {
+            CreatedFormat::Shell
+        } else if self.mailto {
+            CreatedFormat::Mailto { open: self.open }
+        } else {
+            CreatedFormat::Address
+        }
+    }
+}
+
+/// Set when the command was asked for `--json`, so errors are JSON too.
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// Whether slow operations may show a spinner; off with `--quiet` or `--json`.
+static PROGRESS: AtomicBool = AtomicBool::new(false);
+
+fn spinner(message: &str) -> prompt::Spinner {
+    prompt::Spinner::start(message, PROGRESS.load(Ordering::Relaxed))
+}
+
+/// Print an error to stderr, as `{"error": {...}}` in JSON mode.
+fn report_error(kind: &str, message: String, status: Option<u16>) {
+    if JSON_ERRORS.load(Ordering::Relaxed) {
+        let mut error = serde_json::json!({ "kind": kind, "message": message });
+        if let Some(status) = status {
+            error["status"] = serde_json::json!(status);
+        }
+        eprintln!("{}", serde_json::json!({ "error": error }));
+    } else {
+        eprintln!("{}", message);
+    }
+}
+
+fn exit_code(error: &FastmailError) -> i32 {
+    match error {
+        FastmailError::Auth(..) | FastmailError::MissingCapability(_) => EXIT_AUTH,
+        FastmailError::NotFound(_) => EXIT_NOT_FOUND,
+        FastmailError::Build(_) | FastmailError::InvalidInput(_) => EXIT_USAGE,
+        FastmailError::Http(_)
+        | FastmailError::Status(..)
+        | FastmailError::Api(_)
+        | FastmailError::Parse(_) => EXIT_API,
+    }
+}
+
+fn handle_error(context: &str, error: FastmailError) -> ! {
+    if error.status() == Some(401) && !LOGGING_IN.load(Ordering::Relaxed) {
+        handle_invalid_token();
+    }
+    report_error(error.kind(), format!("{}: {}", context, error), error.status());
+    std::process::exit(exit_code(&error));
+}
+
+/// Set while `login` runs, so a rejected token isn't answered with an
+/// offer to log in.
+static LOGGING_IN: AtomicBool = AtomicBool::new(false);
+
+/// Explain a rejected token and, when interactive, offer to log in again.
+fn handle_invalid_token() -> ! {
+    let message = "Your API token is invalid or expired — run 'tmail login' to re-authenticate.";
+    report_error("auth", message.to_string(), Some(401));
+    let json = JSON_ERRORS.load(Ordering::Relaxed);
+    if !json && prompt::is_interactive() && prompt::confirm("Log in now?", true) {
+        login(false);
+        eprintln!("Run the command again to continue.");
+    }
+    std::process::exit(EXIT_AUTH);
+}
+
+fn handle_config_error(error: ConfigError) -> ! {
+    report_error("config", format!("Error: {}", error), None);
+    let code = match error {
+        ConfigError::NotLoggedIn | ConfigError::TokenCommand(_) => EXIT_AUTH,
+        ConfigError::NoConfigDir
+        | ConfigError::Io(..)
+        | ConfigError::Parse(..)
+        | ConfigError::Locked(_) => EXIT_USAGE,
+    };
+    std::process::exit(code);
+}
+
+/// Credentials given on the command line or in the environment, which take
+/// precedence over the saved config.
+struct Credentials {
+    token: Option<String>,
+    account_id: Option<AccountId>,
 }
 
-fn config_path() -> PathBuf {
-    let home = dirs::home_dir().expect("Could not find home directory");
-    let config_dir = home.join(".config").join("tmail");
-    fs::create_dir_all(&config_dir).expect("Could not create config directory");
-    config_dir.join("config.json")
+/// The config to run a command with, exiting with a hint to log in if
+/// there is none. A `--token` or FASTMAIL_TOKEN needs no config file; its
+/// account comes from `--account-id` or else the session.
+fn require_config(creds: &Credentials) -> Config {
+    match (&creds.token, &creds.account_id) {
+        (Some(token), Some(account_id)) => Config::new(token.clone(), account_id.clone()),
+        (Some(token), None) => {
+            let mut config = Config::new(token.clone(), AccountId::from(String::new()));
+            match build_client(&config).get_account_id() {
+                Ok(account_id) => {
+                    config.account_id = account_id;
+                    config
+                }
+                Err(e) => handle_error("Failed to look up account", e),
+            }
+        }
+        (None, account_id) => {
+            let mut config = load_config().unwrap_or_else(|e| handle_config_error(e));
+            if let Some(account_id) = account_id {
+                config.account_id = account_id.clone();
+            }
+            config
+        }
+    }
 }
 
-fn load_config() -> Option<Config> {
-    let path = config_path();
-    let content = fs::read_to_string(path).ok()?;
-    serde_json::from_str(&content).ok()
+/// The config's `http` section, with the proxy falling back to `HTTPS_PROXY`.
+fn effective_http(config: &Config) -> HttpConfig {
+    let mut http = config.http.clone();
+    http.proxy = http
+        .proxy
+        .or_else(|| std::env::var("HTTPS_PROXY").ok())
+        .or_else(|| std::env::var("https_proxy").ok())
+        .filter(|p| !p.is_empty());
+    http
 }
 
-fn save_config(config: &Config) {
-    let path = config_path();
-    let content = serde_json::to_string_pretty(config).expect("Could not serialize config");
-    fs::write(path, content).expect("Could not write config file");
+/// The one place a client is built, so every command gets the same HTTP settings.
+fn build_client(config: &Config) -> FastmailClient {
+    let http = effective_http(config);
+    let mut builder = FastmailClient::builder(&config.api_token).on_advisory(|advisory| {
+        eprintln!("Notice from Fastmail ({}): {}", advisory.header, advisory.value);
+    });
+    if let Some(url) = http.proxy {
+        builder = builder.proxy(url);
+    }
+    if let Some(secs) = http.timeout_secs {
+        builder = builder.timeout(Duration::from_secs(secs));
+    }
+    if let Some(path) = http.ca_certificate {
+        match std::fs::read(&path) {
+            Ok(pem) => builder = builder.add_root_certificate(pem),
+            Err(e) => {
+                eprintln!("Error: Could not read CA certificate {}: {}", path.display(), e);
+                std::process::exit(EXIT_USAGE);
+            }
+        }
+    }
+    if let Some(agent) = http.user_agent {
+        builder = builder.user_agent(agent);
+    }
+    match builder.build() {
+        Ok(client) => client,
+        Err(e) => handle_error("Error", e),
+    }
 }
 
 fn prompt(message: &str) -> String {
@@ -87,172 +647,1775 @@ fn prompt(message: &str) -> String {
     input.trim().to_string()
 }
 
-fn login() {
-    println!("Get your API token from: Fastmail → Settings → Privacy & Security → API tokens");
-    println!("Create a new token with 'Masked Email' scope.\n");
+fn read_token_from_stdin() -> String {
+    let mut input = String::new();
+    io::stdin().read_line(&mut input).unwrap_or_default();
+    input.trim().to_string()
+}
 
-    let token = prompt("Enter API token: ");
+fn login(quiet: bool) {
+    LOGGING_IN.store(true, Ordering::Relaxed);
+    // Piped input (e.g. `echo $TOKEN | tmail login`) is read directly for scripted setup
+    let pasted = if prompt::is_interactive() {
+        println!("Get your API token from: Fastmail → Settings → Privacy & Security → API tokens");
+        println!("Create a new token with 'Masked Email' scope.\n");
+        prompt("Enter API token: ")
+    } else {
+        read_token_from_stdin()
+    };
+    let token = tmail::clean_token(&pasted);
     if token.is_empty() {
         eprintln!("Error: Token cannot be empty");
-        std::process::exit(1);
+        std::process::exit(EXIT_USAGE);
+    }
+    if token != pasted.trim() && !quiet {
+        eprintln!("Note: removed the 'Bearer ' prefix; only the token itself is needed.");
+    }
+    if !tmail::token_looks_valid(&token) {
+        eprintln!("Warning: this doesn't look like a Fastmail API token (they start with 'fmu1-').");
     }
 
-    let client = FastmailClient::new(&token);
+    // An unreadable config is replaced rather than blocking a fresh login,
+    // but its HTTP settings are needed to reach the server
+    let mut probe = load_config()
+        .unwrap_or_else(|_| Config::new(String::new(), AccountId::from(String::new())));
+    probe.api_token = token.clone();
+    let client = build_client(&probe);
 
-    match client.get_account_id() {
-        Ok(account_id) => {
-            let config = Config {
-                api_token: token,
-                account_id,
-            };
-            save_config(&config);
-            println!("Logged in successfully. Config saved to {:?}", config_path());
-        }
-        Err(e) => {
-            eprintln!("Login failed: {}", e);
-            std::process::exit(1);
+    let session = match client.get_session() {
+        Ok(session) => session,
+        Err(e) => handle_error("Login failed", e),
+    };
+
+    // A token scoped only for mail still yields a session, so check the
+    // capability explicitly rather than failing later with an API error
+    let account_id = session
+        .masked_email_account_id()
+        .filter(|_| session.has_masked_email_capability());
+    let Some(account_id) = account_id else {
+        eprintln!("Login failed: your token is missing the Masked Email scope.");
+        eprintln!("Create a new token with 'Masked Email' scope and run 'tmail login' again.");
+        std::process::exit(EXIT_AUTH);
+    };
+    let account_id = AccountId::from(account_id);
+
+    // Keep settings like the proxy when logging in again, re-reading them
+    // under the lock in case another process changed them meanwhile
+    let saved = update_config(|current| match current {
+        Some(mut config) => {
+            config.api_token = token;
+            config.account_id = account_id;
+            // A pasted token replaces any token helper
+            config.token_command = None;
+            config
         }
+        None => Config::new(token, account_id),
+    });
+    if let Err(e) = saved {
+        handle_config_error(e);
     }
+    if !quiet {
+        let path = config_path().unwrap_or_default();
+        println!("Logged in successfully. Config saved to {:?}", path);
+    }
+}
+
+fn version() {
+    println!("tmail {}", env!("CARGO_PKG_VERSION"));
+    println!("commit:  {}", build_info::GIT_COMMIT.unwrap_or("unknown"));
+    println!("rustc:   {}", build_info::RUSTC_VERSION);
 }
 
-fn list(all: bool, json: bool) {
-    let config = load_config().expect("Not logged in. Run 'tmail login' first.");
-    let client = FastmailClient::new(&config.api_token);
+fn show_config(creds: &Credentials) {
+    let config = require_config(creds);
+    let http = effective_http(&config);
+    let path = match &creds.token {
+        Some(_) => "(none; using --token / FASTMAIL_TOKEN)".to_string(),
+        None => config_path().unwrap_or_default().display().to_string(),
+    };
+    let or_default = |value: Option<String>| value.unwrap_or_else(|| "(default)".to_string());
+    println!("Config file:      {}", path);
+    println!("Account ID:       {}", config.account_id);
+    println!("Proxy:            {}", http.proxy.unwrap_or_else(|| "(none)".to_string()));
+    println!("Timeout:          {}", or_default(http.timeout_secs.map(|s| format!("{}s", s))));
+    println!(
+        "CA certificate:   {}",
+        or_default(http.ca_certificate.map(|p| p.display().to_string()))
+    );
+    println!("User agent:       {}", or_default(http.user_agent));
+}
 
-    match client.list_masked_emails(&config.account_id) {
-        Ok(emails) => {
-            let filtered: Vec<&MaskedEmail> = if all {
-                emails.iter().collect()
+fn whoami(creds: &Credentials) {
+    let config = require_config(creds);
+    let client = build_client(&config);
+
+    match client.get_session() {
+        Ok(session) => {
+            let account_id = session.masked_email_account_id().unwrap_or("(none)");
+            let capability = if session.has_masked_email_capability() {
+                "yes"
             } else {
-                emails
-                    .iter()
-                    .filter(|e| e.state.as_deref() == Some("enabled"))
-                    .collect()
+                "no"
             };
+            println!("Username:         {}", session.username);
+            println!("Account ID:       {}", account_id);
+            println!("Masked email:     {}", capability);
+        }
+        Err(e) => handle_error("Token check failed", e),
+    }
+}
 
-            if json {
-                println!("{}", serde_json::to_string_pretty(&filtered).unwrap());
-                return;
-            }
+/// Earliest plausible date; a clock set before it breaks TLS certificate checks.
+const CLOCK_FLOOR: Date = Date {
+    year: 2025,
+    month: 1,
+    day: 1,
+};
 
-            if filtered.is_empty() {
-                println!("No masked emails found.");
-                return;
-            }
+/// Print a pass/fail line for a `doctor` check, with a hint on failure.
+/// Returns whether it passed.
+fn report_check(name: &str, result: Result<String, String>) -> bool {
+    match result {
+        Ok(detail) => {
+            println!("ok    {:<14} {}", name, detail);
+            true
+        }
+        Err(hint) => {
+            println!("FAIL  {:<14} {}", name, hint);
+            false
+        }
+    }
+}
 
-            for email in filtered {
-                let desc = email.description.as_deref().unwrap_or("");
-                let domain = email.for_domain.as_deref().unwrap_or("");
-                let state = email.state.as_deref().unwrap_or("unknown");
-                // Extract date portion from ISO 8601 timestamp (first 10 chars: "2024-01-15")
-                let created = email.created_at.as_deref().map(|s| &s[..10]).unwrap_or("");
+fn doctor(creds: &Credentials) {
+    let mut ok = true;
 
-                if all {
-                    println!("{}\t{}\t{}\t{}\t{}", email.email, created, state, domain, desc);
-                } else {
-                    println!("{}\t{}\t{}\t{}", email.email, created, domain, desc);
-                }
+    let today = Date::today();
+    ok &= report_check(
+        "Clock",
+        if today >= CLOCK_FLOOR {
+            Ok(format!("{} (UTC)", today))
+        } else {
+            Err(format!("system date is {}; fix the clock or TLS will fail", today))
+        },
+    );
+
+    // Later checks need a token, so stop here without one
+    let config = match &creds.token {
+        Some(token) => {
+            report_check("Config", Ok("using --token / FASTMAIL_TOKEN".to_string()));
+            creds.account_id.clone().map(|id| Config::new(token.clone(), id))
+        }
+        None => match load_config() {
+            Ok(config) => {
+                let path = config_path().unwrap_or_default();
+                report_check("Config", Ok(path.display().to_string()));
+                Some(config)
             }
+            Err(e) => {
+                report_check("Config", Err(e.to_string()));
+                std::process::exit(EXIT_USAGE);
+            }
+        },
+    };
+    let client = match &config {
+        Some(config) => build_client(config),
+        None => {
+            let token = creds.token.clone().unwrap_or_default();
+            build_client(&Config::new(token, AccountId::from(String::new())))
+        }
+    };
+
+    let session = match client.get_session() {
+        Ok(session) => {
+            report_check("Token", Ok(format!("signed in as {}", session.username)));
+            session
         }
         Err(e) => {
-            eprintln!("Failed to list masked emails: {}", e);
-            std::process::exit(1);
+            let hint = match &e {
+                FastmailError::Auth(..) => {
+                    format!("{}; run 'tmail login' with a new token", e)
+                }
+                FastmailError::Http(_) => format!(
+                    "{}; check your network, set a proxy with HTTPS_PROXY, \
+                     or check the clock if this is a TLS error",
+                    e
+                ),
+                _ => e.to_string(),
+            };
+            report_check("Token", Err(hint));
+            std::process::exit(EXIT_USAGE);
+        }
+    };
+
+    let capable = session.has_masked_email_capability();
+    ok &= report_check(
+        "Capability",
+        if capable {
+            Ok("token has the Masked Email scope".to_string())
+        } else {
+            Err("token lacks the Masked Email scope; create one with it and run 'tmail login'"
+                .to_string())
+        },
+    );
+
+    let account_id = config
+        .map(|c| c.account_id)
+        .or_else(|| session.masked_email_account_id().map(AccountId::from));
+    ok &= report_check(
+        "API",
+        match account_id {
+            Some(account_id) if capable => match client.list_masked_emails(&account_id) {
+                Ok(emails) => Ok(format!("listed {} masked emails", emails.len())),
+                Err(e) => Err(e.to_string()),
+            },
+            _ => Err("skipped; no masked email account".to_string()),
+        },
+    );
+
+    if !ok {
+        std::process::exit(EXIT_USAGE);
+    }
+}
+
+/// Fetch the list, refreshing the offline cache, or fall back to the cache
+/// when offline or the network is unreachable. Newest emails come first.
+fn fetch_or_cached(
+    config: &Config,
+    offline: bool,
+    quiet: bool,
+) -> Result<Vec<MaskedEmail>, FastmailError> {
+    let cached = |reason: &str| {
+        let cached = cache::load(&cache_path().ok()?, &config.account_id)?;
+        if !quiet {
+            eprintln!(
+                "{}: showing list cached {}",
+                reason,
+                cached.age_description()
+            );
+        }
+        Some(cached.emails)
+    };
+
+    if offline {
+        return cached("Offline").ok_or_else(|| {
+            FastmailError::NotFound(
+                "no cached list; run 'tmail masked list' online first".to_string(),
+            )
+        });
+    }
+
+    let spinner = spinner("Fetching masked emails…");
+    let result = build_client(config).list_masked_emails_with_state(&config.account_id);
+    drop(spinner);
+    match result {
+        Ok(mut result) => {
+            tmail::sort_newest_first(&mut result.emails);
+            if let Ok(path) = cache_path() {
+                cache::store(&path, &config.account_id, &result.emails, &result.state);
+            }
+            Ok(result.emails)
         }
+        Err(FastmailError::Http(e)) => cached("Network error").ok_or(FastmailError::Http(e)),
+        Err(e) => Err(e),
     }
 }
 
-fn create(description: Option<String>, website: Option<String>) {
-    let config = load_config().expect("Not logged in. Run 'tmail login' first.");
-    let client = FastmailClient::new(&config.api_token);
+/// Wrap `text` in an ANSI color escape when color is enabled.
+fn paint(text: &str, code: &str, color: bool) -> String {
+    if color {
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    } else {
+        text.to_string()
+    }
+}
 
-    // Interactive mode if no description provided and stdin is a TTY
-    let (desc, site) = if description.is_none() && prompt::is_interactive() {
-        let desc = prompt::prompt_text(
-            "Description:",
-            Some("What is this masked email for?"),
-            None,
-        );
-        let site = prompt::prompt_text(
-            "Website:",
-            Some("Optional: domain this email is for"),
-            Some("example.com"),
-        );
-        (desc, site)
+/// A state in green when active, yellow when not receiving mail yet or any
+/// more, and red once deleted.
+fn paint_state(state: &str, color: bool) -> String {
+    match state {
+        "enabled" => paint(state, "32", color),
+        "disabled" | "pending" => paint(state, "33", color),
+        "deleted" => paint(state, "31", color),
+        _ => state.to_string(),
+    }
+}
+
+/// A tab-separated row for `list` and `search`. With `show_tags`, tags move
+/// from the description to a final column.
+fn list_row(email: &MaskedEmail, show_state: bool, show_tags: bool, color: bool) -> String {
+    let desc = email.description.as_deref().unwrap_or("");
+    let desc = if show_tags {
+        output::escape_tsv(&tmail::strip_tags(desc))
     } else {
-        (description, website)
+        output::escape_tsv(desc)
     };
+    let domain = output::escape_tsv(email.for_domain.as_deref().unwrap_or(""));
+    let state = paint_state(email.state.as_deref().unwrap_or("unknown"), color);
+    // Extract date portion from ISO 8601 timestamp (first 10 chars: "2024-01-15")
+    let created = email.created_at.as_deref().map(|s| s.get(..10).unwrap_or(s)).unwrap_or("");
 
-    match client.create_masked_email(&config.account_id, desc.as_deref(), site.as_deref()) {
-        Ok(masked) => {
-            println!("{}", masked.email);
+    let mut row = if show_state {
+        format!("{}\t{}\t{}\t{}\t{}", email.email, created, state, domain, desc)
+    } else {
+        format!("{}\t{}\t{}\t{}", email.email, created, domain, desc)
+    };
+    if show_tags {
+        let tags: Vec<String> = email.tags().iter().map(|tag| format!("#{}", tag)).collect();
+        row.push('\t');
+        row.push_str(&output::escape_tsv(&tags.join(" ")));
+    }
+    row
+}
+
+/// Group emails by `for_domain`, or by its registrable part with
+/// `subdomains`, sorted by domain with the domain-less group last. Order
+/// within a group is kept.
+fn group_by_domain(
+    emails: Vec<&MaskedEmail>,
+    subdomains: bool,
+) -> Vec<(Option<String>, Vec<&MaskedEmail>)> {
+    let mut groups: Vec<(Option<String>, Vec<&MaskedEmail>)> = Vec::new();
+    for email in emails {
+        let domain = email.for_domain.as_deref().filter(|d| !d.is_empty());
+        let domain = match domain {
+            Some(domain) if subdomains => tmail::registrable_domain(domain),
+            domain => domain.map(str::to_string),
+        };
+        match groups.iter_mut().find(|(d, _)| *d == domain) {
+            Some((_, group)) => group.push(email),
+            None => groups.push((domain, vec![email])),
         }
-        Err(e) => {
-            eprintln!("Failed to create masked email: {}", e);
-            std::process::exit(1);
+    }
+    groups.sort_by(|(a, _), (b, _)| (a.is_none(), a).cmp(&(b.is_none(), b)));
+    groups
+}
+
+/// Why nothing was shown: an empty account, or `total` aliases of which none
+/// matched `criteria`.
+fn nothing_found(total: usize, criteria: &str) -> String {
+    if total == 0 {
+        "You have no masked emails yet. Create one with 'tmail masked create'.".to_string()
+    } else {
+        format!("No masked emails match {} ({} total in account).", criteria, total)
+    }
+}
+
+/// Print `content`, or write it atomically to `path` unless that is `-`.
+fn write_output(path: Option<&PathBuf>, content: &str) {
+    match path.filter(|p| p.as_os_str() != "-") {
+        Some(path) => {
+            if let Err(e) = output::write_atomic(path, content) {
+                eprintln!("Error: could not write {}: {}", path.display(), e);
+                std::process::exit(EXIT_USAGE);
+            }
         }
+        None => print!("{}", content),
     }
 }
 
-fn delete(email: Option<String>) {
-    let Some(email) = email else {
-        eprintln!("Error: No email address specified.");
-        eprintln!();
-        eprintln!("Usage: tmail masked delete <EMAIL>");
-        eprintln!();
-        eprintln!("To see your masked emails, run:");
-        eprintln!("  tmail masked list");
-        eprintln!();
-        eprintln!("To include disabled/deleted emails:");
-        eprintln!("  tmail masked list --all");
-        std::process::exit(1);
+fn list(creds: &Credentials, args: ListArgs, quiet: bool, color: bool) {
+    let to_file = args.output.as_ref().is_some_and(|p| p.as_os_str() != "-");
+    let color = color && !to_file;
+    let config = require_config(creds);
+    let states = args.states();
+    // The state column is only redundant when showing enabled emails alone
+    let show_state = states
+        .as_ref()
+        .is_none_or(|s| s.len() > 1 || !s.contains("enabled"));
+
+    let created_by = args.created_by.as_deref().map(str::to_lowercase);
+    let keep = |e: &MaskedEmail| {
+        let state_ok = states
+            .as_ref()
+            .is_none_or(|s| e.state.as_deref().is_some_and(|state| s.contains(state)));
+        let created_by_ok = created_by.as_deref().is_none_or(|filter| {
+            e.created_by
+                .as_deref()
+                .is_some_and(|c| c.to_lowercase().contains(filter))
+        });
+        let domain_ok = args.domain.as_deref().is_none_or(|filter| {
+            e.for_domain
+                .as_deref()
+                .is_some_and(|d| tmail::domain_matches(d, filter, args.match_subdomains))
+        });
+        // Undated entries can't be placed in a range, so any bound excludes them
+        let date_ok = (args.since.is_none() && args.until.is_none())
+            || e.created_date().is_some_and(|date| {
+                args.since.is_none_or(|since| date >= since)
+                    && args.until.is_none_or(|until| date <= until)
+            });
+        let tag_ok = args.tag.as_deref().is_none_or(|tag| e.has_tag(tag));
+        state_ok && created_by_ok && domain_ok && date_ok && tag_ok
     };
 
-    let config = load_config().expect("Not logged in. Run 'tmail login' first.");
-    let client = FastmailClient::new(&config.api_token);
+    if let Some(page) = args.page {
+        list_page(&config, &args, page, show_state, quiet, color);
+        return;
+    }
 
-    // Find the email in the list to get its ID
-    let emails = match client.list_masked_emails(&config.account_id) {
-        Ok(emails) => emails,
-        Err(e) => {
-            eprintln!("Failed to list masked emails: {}", e);
-            std::process::exit(1);
+    // Stream straight from the server when printing, rather than holding
+    // the whole account in memory
+    let sorted = args.sort.is_some() || args.reverse;
+    if args.json_lines && !args.offline && !to_file && !sorted {
+        stream_json_lines(&config, keep, args.limit);
+        return;
+    }
+
+    match fetch_or_cached(&config, args.offline, quiet) {
+        Ok(emails) => {
+            let mut filtered: Vec<&MaskedEmail> = emails.iter().filter(|e| keep(e)).collect();
+            // Stable sorts, so ties keep the newest-first order
+            match (&args.sort, args.reverse) {
+                (Some(order), reverse) => order.sort(&mut filtered, reverse),
+                (None, true) => filtered.reverse(),
+                (None, false) => {}
+            }
+
+            if args.count_only {
+                write_output(args.output.as_ref(), &count_line(filtered.len(), args.json));
+                return;
+            }
+
+            let hidden = match args.limit {
+                Some(n) if filtered.len() > n => {
+                    let hidden = filtered.len() - n;
+                    filtered.truncate(n);
+                    hidden
+                }
+                _ => 0,
+            };
+
+            if filtered.is_empty() && !args.json && !args.json_lines {
+                if !quiet {
+                    println!("{}", nothing_found(emails.len(), "the filter"));
+                }
+            } else {
+                write_output(args.output.as_ref(), &list_lines(&args, filtered, show_state, color));
+            }
+
+            if hidden > 0 && !quiet {
+                eprintln!("… and {} more", hidden);
+            }
         }
-    };
+        Err(e) => handle_error("Failed to list masked emails", e),
+    }
+}
 
-    let masked = emails.iter().find(|e| e.email == email);
-    let Some(masked) = masked else {
-        eprintln!("Error: Masked email '{}' not found.", email);
-        eprintln!();
-        eprintln!("To see your masked emails, run:");
-        eprintln!("  tmail masked list --all");
-        std::process::exit(1);
+/// One page of `list --page`, queried with the state filter on the server
+/// so large accounts aren't fetched whole.
+fn list_page(
+    config: &Config,
+    args: &ListArgs,
+    page: NonZeroUsize,
+    show_state: bool,
+    quiet: bool,
+    color: bool,
+) {
+    let limit = args.limit.unwrap_or_default();
+    let mut states: Vec<&str> = args.states().map(|s| s.into_iter().collect()).unwrap_or_default();
+    states.sort_unstable();
+    let position = (page.get() - 1).saturating_mul(limit);
+    let result = build_client(config).query_masked_emails(
+        &config.account_id,
+        &states,
+        position,
+        limit,
+    );
+    let page = match result {
+        Ok(page) => page,
+        Err(e) => handle_error("Failed to list masked emails", e),
     };
+    let total = page.total.map(|t| format!(" of {}", t)).unwrap_or_default();
+    if page.emails.is_empty() && !args.json && !args.json_lines {
+        if !quiet {
+            println!("No masked emails from position {}{}.", page.position + 1, total);
+        }
+        return;
+    }
 
-    let Some(id) = &masked.id else {
-        eprintln!("Error: Masked email has no ID.");
-        std::process::exit(1);
-    };
+    let count = page.emails.len();
+    let emails: Vec<&MaskedEmail> = page.emails.iter().collect();
+    write_output(args.output.as_ref(), &list_lines(args, emails, show_state, color));
+    if count > 0 && !quiet {
+        eprintln!("Showing {}–{}{}", page.position + 1, page.position + count, total);
+    }
+}
 
-    match client.delete_masked_email(&config.account_id, id) {
-        Ok(()) => {
-            println!("Archived: {}", email);
+/// `list`'s output for `emails` in the format its flags ask for, one
+/// newline-terminated line per row.
+fn list_lines(args: &ListArgs, emails: Vec<&MaskedEmail>, show_state: bool, color: bool) -> String {
+    let mut lines = Vec::new();
+    if args.json {
+        lines.push(serde_json::to_string_pretty(&emails).unwrap());
+    } else if args.json_lines {
+        lines.extend(emails.iter().map(|e| serde_json::to_string(e).unwrap()));
+    } else if args.porcelain {
+        lines.extend(emails.iter().map(|e| output::porcelain_row(e)));
+    } else if let Some(template) = &args.format {
+        lines.extend(emails.iter().map(|e| template.render(e)));
+    } else if args.addresses_only {
+        lines.extend(emails.iter().map(|e| e.email.clone()));
+    } else if args.group_by_domain {
+        for (domain, emails) in group_by_domain(emails, args.match_subdomains) {
+            lines.push(paint(domain.as_deref().unwrap_or("(no domain)"), "1", color));
+            for email in emails {
+                lines.push(format!("  {}", email));
+            }
         }
-        Err(e) => {
-            eprintln!("Failed to archive masked email: {}", e);
-            std::process::exit(1);
+    } else {
+        for email in emails {
+            let row = list_row(email, show_state, args.show_tags, color);
+            // Provenance is shown with --all, where aliases from every client mix
+            if args.all {
+                let created_by = email.created_by.as_deref().unwrap_or("");
+                lines.push(format!("{}\t{}", row, created_by));
+            } else {
+                lines.push(row);
+            }
         }
     }
+    lines.iter().map(|line| format!("{}\n", line)).collect()
 }
 
-fn main() {
-    let cli = Cli::parse();
-
-    match cli.command {
-        Commands::Login => login(),
-        Commands::Masked { command } => match command {
-            MaskedCommands::List { all, json } => list(all, json),
-            MaskedCommands::Create { description, website } => create(description, website),
-            MaskedCommands::Delete { email } => delete(email),
+/// Print each masked email that `keep` accepts as one line of JSON, in
+/// server order, fetching pages only as they're needed.
+fn stream_json_lines(config: &Config, keep: impl Fn(&MaskedEmail) -> bool, limit: Option<usize>) {
+    let limit = limit.unwrap_or(usize::MAX);
+    if limit == 0 {
+        return;
+    }
+    let client = build_client(config);
+    let mut stdout = io::stdout().lock();
+    let mut shown = 0;
+    for email in client.iter_masked_emails(&config.account_id) {
+        let email = match email {
+            Ok(email) => email,
+            Err(e) => handle_error("Failed to list masked emails", e),
+        };
+        if !keep(&email) {
+            continue;
+        }
+        let line = serde_json::to_string(&email).unwrap();
+        // A closed pipe, e.g. from `head`, just ends the output
+        if writeln!(stdout, "{}", line).and_then(|()| stdout.flush()).is_err() {
+            return;
+        }
+        shown += 1;
+        if shown == limit {
+            return;
+        }
+    }
+}
+
+/// Masked emails count toward the limit until they're deleted.
+fn active_count(emails: &[MaskedEmail]) -> u64 {
+    emails.iter().filter(|e| e.state.as_deref() != Some("deleted")).count() as u64
+}
+
+fn count(creds: &Credentials, quota: bool, quiet: bool) {
+    let config = require_config(creds);
+    let emails = match fetch_or_cached(&config, false, quiet) {
+        Ok(emails) => emails,
+        Err(e) => handle_error("Failed to list masked emails", e),
+    };
+    let active = active_count(&emails);
+    if !quota {
+        println!("{}", active);
+        return;
+    }
+
+    let session = match build_client(&config).get_session() {
+        Ok(session) => session,
+        Err(e) => handle_error("Failed to fetch session", e),
+    };
+    match session.masked_email_limit() {
+        Some(limit) => println!("{} / {} used", active, limit),
+        None => println!("{} used (no limit reported)", active),
+    }
+}
+
+fn report(creds: &Credentials, json: bool, quiet: bool) {
+    let config = require_config(creds);
+    let emails = match fetch_or_cached(&config, false, quiet) {
+        Ok(emails) => emails,
+        Err(e) => handle_error("Failed to list masked emails", e),
+    };
+    let report = output::Report::new(&emails);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else if report.total == 0 {
+        println!("{}", nothing_found(0, ""));
+    } else {
+        print!("{}", report.render());
+    }
+}
+
+/// Warn on stderr when a create couldn't remove its retry tag, so the
+/// description still ends with it.
+fn warn_retry_tag(masked: &MaskedEmail) {
+    if let Some(tag) = masked.retry_tag() {
+        eprintln!(
+            "Warning: couldn't remove {} from the description; fix it with \
+             `tmail masked edit {} --description <TEXT>`",
+            tag, masked.email
+        );
+    }
+}
+
+/// Warn on stderr when the account is close to its masked email limit.
+/// Best effort: skipped if the server doesn't report a limit.
+fn warn_near_quota(client: &FastmailClient, config: &Config) {
+    let Some(limit) = client.get_session().ok().and_then(|s| s.masked_email_limit()) else {
+        return;
+    };
+    let Ok(emails) = client.list(&config.account_id) else {
+        return;
+    };
+    let active = active_count(&emails);
+    if active + QUOTA_WARNING_MARGIN >= limit {
+        eprintln!(
+            "Warning: {} of {} masked emails used; delete unused ones to keep creating",
+            active, limit
+        );
+    }
+}
+
+/// A match count as printed by `--count-only`: the bare number, or
+/// `{"count": N}` with `--json`.
+fn count_line(count: usize, json: bool) -> String {
+    if json {
+        format!("{}\n", serde_json::json!({ "count": count }))
+    } else {
+        format!("{}\n", count)
+    }
+}
+
+fn search(creds: &Credentials, args: SearchArgs, quiet: bool, color: bool) {
+    let term = &args.term;
+    let pattern = if args.regex { term.clone() } else { regex::escape(term) };
+    let pattern = match regex::RegexBuilder::new(&pattern).case_insensitive(true).build() {
+        Ok(pattern) => pattern,
+        Err(e) => {
+            eprintln!("Error: invalid regex: {}", e);
+            std::process::exit(EXIT_USAGE);
+        }
+    };
+
+    let config = require_config(creds);
+    let emails = match fetch_or_cached(&config, false, quiet) {
+        Ok(emails) => emails,
+        Err(e) => handle_error("Failed to list masked emails", e),
+    };
+    let matches: Vec<&MaskedEmail> = emails
+        .iter()
+        .filter(|e| {
+            [&e.description, &e.for_domain]
+                .into_iter()
+                .any(|field| field.as_deref().is_some_and(|f| pattern.is_match(f)))
+        })
+        .filter(|e| args.tag.as_deref().is_none_or(|tag| e.has_tag(tag)))
+        .collect();
+
+    if args.count_only {
+        print!("{}", count_line(matches.len(), args.json));
+    } else if args.json {
+        println!("{}", serde_json::to_string_pretty(&matches).unwrap());
+    } else if matches.is_empty() {
+        if !quiet {
+            println!("{}", nothing_found(emails.len(), &format!("'{}'", term)));
+        }
+    } else {
+        for email in matches {
+            println!("{}", list_row(email, true, args.show_tags, color));
+        }
+    }
+}
+
+/// Split a `--website` value into a domain and url. A full URL is kept as
+/// the url and its host becomes the domain.
+fn split_website(website: Option<String>) -> (Option<String>, Option<String>) {
+    match website {
+        Some(website) => match tmail::domain_from_url(&website) {
+            Some(host) => (Some(host), Some(website.trim().to_string())),
+            None => (Some(website), None),
+        },
+        None => (None, None),
+    }
+}
+
+/// Print a created email: just the address for piping, or the full object.
+fn print_created(masked: &MaskedEmail, format: CreatedFormat) {
+    match format {
+        CreatedFormat::Address => println!("{}", masked.email),
+        CreatedFormat::Json => println!("{}", serde_json::to_string_pretty(masked).unwrap()),
+        CreatedFormat::Shell => print_shell_exports(masked),
+        CreatedFormat::Mailto { open } => {
+            let url = output::mailto_url(&masked.email);
+            println!("{}", url);
+            if open {
+                if let Err(e) = webbrowser::open(&url) {
+                    eprintln!("Could not open a mail client ({}).", e);
+                    std::process::exit(EXIT_API);
+                }
+            }
+        }
+    }
+}
+
+/// Print `export` lines for `create --shell`.
+fn print_shell_exports(masked: &MaskedEmail) {
+    let id = masked.id.as_ref().map_or("", |id| id.as_ref());
+    let lines = [("TMAIL_EMAIL", masked.email.as_str()), ("TMAIL_ID", id)]
+        .map(|(name, value)| output::shell_export(name, value));
+    // Fastmail's addresses and ids never need escaping, so refuse rather than guess
+    let [Some(email), Some(id)] = lines else {
+        eprintln!("Error: Masked email '{}' isn't safe to print for a shell.", masked.email);
+        std::process::exit(EXIT_API);
+    };
+    println!("{}", email);
+    println!("{}", id);
+}
+
+/// Append a `#tag` token to `description` for each of `tags` it doesn't
+/// already have.
+fn add_tags(description: Option<String>, tags: &[String]) -> Option<String> {
+    if tags.is_empty() {
+        return description;
+    }
+    let mut description = description.unwrap_or_default();
+    for tag in tags {
+        let present = tmail::description_tags(&description)
+            .iter()
+            .any(|t| t.eq_ignore_ascii_case(tag));
+        if !present {
+            if !description.is_empty() {
+                description.push(' ');
+            }
+            description.push('#');
+            description.push_str(tag);
+        }
+    }
+    Some(description).filter(|d| !d.is_empty())
+}
+
+fn create(creds: &Credentials, mut args: CreateArgs, quiet: bool) {
+    let format = args.created_format();
+    if args.wait && args.state != "enabled" {
+        eprintln!("Error: --wait only applies to masked emails created enabled.");
+        std::process::exit(EXIT_USAGE);
+    }
+    args.description = match prompt::read_text_arg(
+        args.description,
+        args.description_file.as_deref(),
+        io::stdin(),
+    ) {
+        Ok(description) => description,
+        Err(e) => {
+            eprintln!("Error: Failed to read description: {}", e);
+            std::process::exit(EXIT_USAGE);
+        }
+    };
+    let config = require_config(creds);
+    let client = build_client(&config);
+    let interactive = prompt::is_interactive();
+
+    // Interactive mode if no description provided and stdin is a TTY
+    let prompted = args.description.is_none() && interactive && !args.no_description;
+    let (desc, site) = if prompted {
+        let desc = prompt::prompt_text(
+            "Description:",
+            Some("What is this masked email for?"),
+            None,
+            None,
+        );
+        let site = prompt::prompt_text(
+            "Website:",
+            Some("Optional: domain this email is for"),
+            Some("example.com"),
+            None,
+        );
+        (desc, site)
+    } else {
+        (args.description, args.website)
+    };
+    let (site, site_url) = split_website(site);
+    let url = args.url.or(site_url);
+    let desc = add_tags(desc, &args.tag);
+
+    // Unlabeled aliases are impossible to audit later, so scripts must opt in
+    let unlabeled = desc.as_deref().is_none_or(|d| d.trim().is_empty());
+    if unlabeled && !prompted && !args.no_description {
+        if config.require_description {
+            eprintln!("Error: A description is required (require_description is set in the config).");
+            eprintln!("Pass --description, or --no-description to create one without.");
+            std::process::exit(EXIT_USAGE);
+        }
+        if site.is_none() {
+            eprintln!("Error: No description or website given.");
+            eprintln!("Pass --description and/or --website, or --no-description to create one without.");
+            std::process::exit(EXIT_USAGE);
+        }
+    }
+
+    let domain = site.as_deref().and_then(|s| tmail::normalize_domain(s).ok().flatten());
+    let check_duplicates = domain.is_some() && (interactive || args.warn_duplicates);
+    let emails = if args.if_not_exists || check_duplicates {
+        match client.list(&config.account_id) {
+            Ok(emails) => ListResult::from(emails),
+            Err(e) => handle_error("Failed to list masked emails", e),
+        }
+    } else {
+        ListResult::from(Vec::new())
+    };
+
+    if args.if_not_exists {
+        let wanted = desc.as_deref().unwrap_or("");
+        let existing = emails
+            .enabled()
+            .find(|e| e.description.as_deref().unwrap_or("") == wanted);
+        if let Some(existing) = existing {
+            if !quiet {
+                eprintln!("Reused existing masked email");
+            }
+            print_created(existing, format);
+            return;
+        }
+    }
+
+    if let Some(domain) = domain.as_deref().filter(|_| check_duplicates) {
+        let same_domain: Vec<&MaskedEmail> = emails
+            .by_domain(domain)
+            .filter(|e| e.state.as_deref() == Some("enabled"))
+            .collect();
+        if !same_domain.is_empty() {
+            eprintln!("Existing aliases for {}:", domain);
+            for e in &same_domain {
+                eprintln!("  {}", e.summary_line());
+            }
+            if interactive {
+                let message = format!(
+                    "You already have {} aliases for this domain — create another?",
+                    same_domain.len()
+                );
+                if !prompt::confirm(&message, false) {
+                    eprintln!("Aborted.");
+                    return;
+                }
+            }
+        }
+    }
+
+    let email_prefix = args
+        .prefix
+        .or_else(|| domain.as_deref().and_then(tmail::prefix_from_domain));
+    let spec = CreateSpec {
+        description: desc,
+        for_domain: site,
+        url,
+        email_prefix,
+        state: Some(args.state),
+    };
+    match client.create(&config.account_id, &spec) {
+        Ok(masked) => {
+            if args.if_not_exists && !quiet {
+                eprintln!("Created new masked email");
+            }
+            let masked = if args.wait {
+                wait_until_enabled(&client, &config, masked)
+            } else {
+                masked
+            };
+            print_created(&masked, format);
+            warn_retry_tag(&masked);
+            warn_near_quota(&client, &config);
+        }
+        Err(e) => handle_error("Failed to create masked email", e),
+    }
+}
+
+/// How long `create --wait` polls before giving up.
+const WAIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Poll a just-created masked email, backing off between checks, until the
+/// server reports it enabled. Exits if it isn't within `WAIT_TIMEOUT`.
+fn wait_until_enabled(
+    client: &FastmailClient,
+    config: &Config,
+    created: MaskedEmail,
+) -> MaskedEmail {
+    let Some(id) = created.id.clone() else {
+        return created;
+    };
+    let spinner = spinner("Waiting for the masked email to be enabled…");
+    let start = Instant::now();
+    let mut delay = Duration::from_millis(250);
+    loop {
+        // The create response may echo the requested state, so always ask
+        let masked = match client.get_masked_email(&config.account_id, &id) {
+            Ok(masked) => masked,
+            Err(e) => {
+                drop(spinner);
+                handle_error("Failed to check the new masked email", e)
+            }
+        };
+        if masked.state.as_deref() == Some("enabled") {
+            return masked;
+        }
+        if start.elapsed() >= WAIT_TIMEOUT {
+            drop(spinner);
+            eprintln!(
+                "Error: {} was still {} after {}s.",
+                masked.email,
+                masked.state.as_deref().unwrap_or("not enabled"),
+                WAIT_TIMEOUT.as_secs()
+            );
+            std::process::exit(EXIT_API);
+        }
+        std::thread::sleep(delay);
+        delay = (delay * 2).min(Duration::from_secs(4));
+    }
+}
+
+/// Look up a masked email and its id by address, exiting with a hint if it
+/// doesn't exist.
+fn resolve_alias(
+    store: &impl MaskedEmailStore,
+    config: &Config,
+    email: &str,
+) -> (MaskedEmail, MaskedEmailId) {
+    let emails = match store.list(&config.account_id) {
+        Ok(emails) => emails,
+        Err(e) => handle_error("Failed to list masked emails", e),
+    };
+
+    let Some(masked) = ListResult::from(emails).find(email).cloned() else {
+        eprintln!("Error: Masked email '{}' not found.", email);
+        eprintln!();
+        eprintln!("To see your masked emails, run:");
+        eprintln!("  tmail masked list --all");
+        std::process::exit(EXIT_NOT_FOUND);
+    };
+
+    let Some(id) = masked.id.clone() else {
+        eprintln!("Error: Masked email has no ID.");
+        std::process::exit(EXIT_API);
+    };
+    (masked, id)
+}
+
+/// The change to request for one field: unchanged values are kept and an
+/// empty value clears the field.
+fn field_update(new: Option<String>, current: Option<&str>) -> UpdateSpec {
+    match new {
+        Some(new) if new == current.unwrap_or_default() => UpdateSpec::Keep,
+        Some(new) if new.is_empty() => UpdateSpec::Clear,
+        Some(new) => UpdateSpec::Set(new),
+        None => UpdateSpec::Keep,
+    }
+}
+
+fn edit(
+    creds: &Credentials,
+    email: String,
+    description: Option<String>,
+    clear_description: bool,
+    website: Option<String>,
+    quiet: bool,
+) {
+    let config = require_config(creds);
+    let client = build_client(&config);
+    let (masked, id) = resolve_alias(&client, &config, &email);
+    let current_desc = masked.description.clone().unwrap_or_default();
+    let current_domain = masked.for_domain.clone().unwrap_or_default();
+    let description = if clear_description {
+        Some(String::new())
+    } else {
+        description
+    };
+
+    let (desc, site) = if description.is_none() && website.is_none() {
+        if !prompt::is_interactive() {
+            eprintln!("Error: Nothing to change. Pass --description, --clear-description and/or --website.");
+            std::process::exit(EXIT_USAGE);
+        }
+        // Clearing a prompt clears the field, so empty answers are kept
+        let desc = prompt::prompt_text("Description:", None, None, Some(&current_desc))
+            .unwrap_or_default();
+        let site = prompt::prompt_text("Website:", None, Some("example.com"), Some(&current_domain))
+            .unwrap_or_default();
+        (Some(desc), Some(site))
+    } else {
+        (description, website)
+    };
+
+    let (site, url) = split_website(site);
+    let desc = field_update(desc, masked.description.as_deref());
+    let site = field_update(site, masked.for_domain.as_deref());
+    let url = field_update(url, masked.url.as_deref());
+    if [&desc, &site, &url].into_iter().all(|u| *u == UpdateSpec::Keep) {
+        if !quiet {
+            println!("No changes.");
+        }
+        return;
+    }
+
+    match client.update(&config.account_id, &id, desc, site, url) {
+        Ok(()) => {
+            if !quiet {
+                println!("Updated: {}", email);
+            }
+        }
+        Err(e) => handle_error("Failed to update masked email", e),
+    }
+}
+
+fn delete(creds: &Credentials, email: Option<String>, quiet: bool) {
+    let Some(email) = email else {
+        eprintln!("Error: No email address specified.");
+        eprintln!();
+        eprintln!("Usage: tmail masked delete <EMAIL>");
+        eprintln!();
+        eprintln!("To see your masked emails, run:");
+        eprintln!("  tmail masked list");
+        eprintln!();
+        eprintln!("To include disabled/deleted emails:");
+        eprintln!("  tmail masked list --all");
+        std::process::exit(EXIT_USAGE);
+    };
+
+    let config = require_config(creds);
+    let client = build_client(&config);
+
+    let (masked, id) = resolve_alias(&client, &config, &email);
+
+    // Only enabled and pending aliases change; anything else would be a no-op set
+    match masked.state.as_deref() {
+        Some("disabled") => {
+            if !quiet {
+                println!("{} is already archived.", email);
+            }
+            return;
+        }
+        Some("deleted") => {
+            eprintln!("Error: Masked email '{}' is deleted.", email);
+            eprintln!("To restore it, open its settings in Fastmail:");
+            eprintln!("  tmail masked open {}", email);
+            std::process::exit(EXIT_USAGE);
+        }
+        _ => {}
+    }
+
+    match client.disable(&config.account_id, &id) {
+        Ok(()) => {
+            if !quiet {
+                println!("Archived: {}", email);
+            }
+        }
+        Err(e) => handle_error("Failed to archive masked email", e),
+    }
+}
+
+/// Move every alias for `domain` to `state` (`enabled` or `disabled`) in
+/// one batch, after listing them for confirmation.
+fn set_domain_state(
+    creds: &Credentials,
+    domain: &str,
+    state: &str,
+    yes: bool,
+    bulk: &BulkArgs,
+    quiet: bool,
+) {
+    let domain = match tmail::normalize_domain(domain) {
+        Ok(Some(domain)) => domain,
+        Ok(None) => {
+            eprintln!("Error: --domain can't be empty.");
+            std::process::exit(EXIT_USAGE);
+        }
+        Err(e) => handle_error("Error", e),
+    };
+    let config = require_config(creds);
+    let client = build_client(&config);
+    let emails = match client.list(&config.account_id) {
+        Ok(emails) => ListResult::from(emails),
+        Err(e) => handle_error("Failed to list masked emails", e),
+    };
+
+    // Deleted aliases stay deleted, and ones already in `state` are left alone
+    let targets: Vec<&MaskedEmail> = emails
+        .by_domain(&domain)
+        .filter(|e| e.id.is_some())
+        .filter(|e| e.state.as_deref().is_none_or(|s| s != "deleted" && s != state))
+        .collect();
+    let (verb, title, done) = match state {
+        "enabled" => ("enable", "Enable", "Enabled"),
+        _ => ("disable", "Disable", "Disabled"),
+    };
+    if targets.is_empty() {
+        if !quiet {
+            println!("No masked emails to {} for {}.", verb, domain);
+        }
+        return;
+    }
+    if bulk.dry_run {
+        print_plan(&format!("{}d", verb), &targets);
+        return;
+    }
+
+    if !yes {
+        if !prompt::is_interactive() {
+            eprintln!("Error: Refusing to {} without confirmation. Pass --yes to proceed.", verb);
+            std::process::exit(EXIT_USAGE);
+        }
+        eprintln!("These masked emails will be {}d:", verb);
+        for masked in &targets {
+            eprintln!("  {}", masked.summary_line());
+        }
+        let message = format!("{} {} masked emails?", title, targets.len());
+        if !prompt::confirm(&message, false) {
+            eprintln!("Aborted.");
+            return;
+        }
+    }
+
+    let ids: Vec<MaskedEmailId> = targets
+        .iter()
+        .map(|masked| masked.id.clone().expect("targets have ids"))
+        .collect();
+    let results = bulk_results(
+        &ids,
+        bulk.fail_fast,
+        || client.set_state_many(&config.account_id, &ids, state),
+        |_, id| {
+            client.set_state_many(&config.account_id, std::slice::from_ref(id), state)?.remove(0)
+        },
+    );
+    let results = match results {
+        Ok(results) => results,
+        Err(e) => handle_error(&format!("Failed to {} masked emails", verb), e),
+    };
+
+    let mut failed = 0;
+    let processed = results.len();
+    for (masked, result) in targets.iter().zip(results) {
+        match result {
+            Ok(()) => {
+                if !quiet {
+                    println!("{}: {}", done, masked.email);
+                }
+            }
+            Err(e) => {
+                failed += 1;
+                eprintln!("Failed to {} {}: {}", verb, masked.email, e);
+            }
+        }
+    }
+    finish_bulk(targets.len(), processed, failed);
+}
+
+/// Results for `items`: from one batch call, or with `fail_fast` from one
+/// call per item, stopping after the first failure.
+fn bulk_results<T, R, E>(
+    items: &[T],
+    fail_fast: bool,
+    batch: impl FnOnce() -> Result<Vec<Result<R, E>>, FastmailError>,
+    single: impl Fn(usize, &T) -> Result<R, E>,
+) -> Result<Vec<Result<R, E>>, FastmailError> {
+    let _spinner = spinner(&format!("Processing {} masked emails…", items.len()));
+    if !fail_fast {
+        return batch();
+    }
+    let mut results = Vec::new();
+    for (i, item) in items.iter().enumerate() {
+        let result = single(i, item);
+        let failed = result.is_err();
+        results.push(result);
+        if failed {
+            break;
+        }
+    }
+    Ok(results)
+}
+
+/// Note what `--fail-fast` left unprocessed, and exit non-zero if any item
+/// failed.
+fn finish_bulk(total: usize, processed: usize, failed: usize) {
+    if processed < total {
+        eprintln!("Stopped at the first failure; {} not processed", total - processed);
+    }
+    if failed > 0 {
+        std::process::exit(EXIT_API);
+    }
+}
+
+fn destroy(creds: &Credentials, addresses: Vec<String>, yes: bool, bulk: &BulkArgs, quiet: bool) {
+    let config = require_config(creds);
+    let client = build_client(&config);
+    let emails = match client.list(&config.account_id) {
+        Ok(emails) => ListResult::from(emails),
+        Err(e) => handle_error("Failed to list masked emails", e),
+    };
+
+    // Addresses differing only in case name the same alias, so dedupe on the match
+    let mut seen = HashSet::new();
+    let mut targets = Vec::new();
+    for address in &addresses {
+        let Some(masked) = emails.find(address).filter(|e| e.id.is_some()) else {
+            eprintln!("Error: Masked email '{}' not found.", address);
+            std::process::exit(EXIT_NOT_FOUND);
+        };
+        if seen.insert(masked.email.as_str()) {
+            targets.push(masked);
+        }
+    }
+
+    if bulk.dry_run {
+        print_plan("destroyed", &targets);
+    } else if confirm_destroy(&targets, yes) {
+        destroy_all(&client, &config, &targets, bulk.fail_fast, quiet);
+    }
+}
+
+/// Number and list `targets` for `--dry-run`, then say how many would be
+/// `done`.
+fn print_plan(done: &str, targets: &[&MaskedEmail]) {
+    for (n, masked) in (1..).zip(targets) {
+        println!("{:>4}. {}", n, masked.summary_line());
+    }
+    println!("{} masked emails would be {}.", targets.len(), done);
+}
+
+/// Ask before destroying `targets`, or just announce them with `--yes`.
+/// Exits when confirmation is needed but stdin isn't interactive.
+fn confirm_destroy(targets: &[&MaskedEmail], yes: bool) -> bool {
+    // Destroying can't be undone, so show exactly what will go
+    if yes {
+        eprintln!("Destroying {} masked emails", targets.len());
+        return true;
+    }
+    if !prompt::is_interactive() {
+        eprintln!("Error: Refusing to destroy without confirmation. Pass --yes to proceed.");
+        std::process::exit(EXIT_USAGE);
+    }
+    eprintln!("These masked emails will be permanently destroyed:");
+    for masked in targets {
+        eprintln!("  {}", masked.summary_line());
+    }
+    let message = format!("Type {} or 'yes' to destroy them:", targets.len());
+    let answer = prompt::prompt_text(&message, None, None, None).unwrap_or_default();
+    if answer != "yes" && answer != targets.len().to_string() {
+        eprintln!("Aborted.");
+        return false;
+    }
+    true
+}
+
+/// Destroy `targets` in one batch, reporting each; exits if any failed.
+fn destroy_all(
+    store: &impl MaskedEmailStore,
+    config: &Config,
+    targets: &[&MaskedEmail],
+    fail_fast: bool,
+    quiet: bool,
+) {
+    let ids: Vec<MaskedEmailId> = targets
+        .iter()
+        .map(|masked| masked.id.clone().expect("targets have ids"))
+        .collect();
+    let results = bulk_results(
+        &ids,
+        fail_fast,
+        || store.destroy_many(&config.account_id, &ids),
+        |_, id| store.destroy(&config.account_id, id),
+    );
+    let results = match results {
+        Ok(results) => results,
+        Err(e) => handle_error("Failed to destroy masked emails", e),
+    };
+
+    let mut failed = 0;
+    let processed = results.len();
+    for (masked, result) in targets.iter().zip(results) {
+        match result {
+            Ok(()) => {
+                if !quiet {
+                    println!("Destroyed: {}", masked.email);
+                }
+            }
+            Err(e) => {
+                failed += 1;
+                eprintln!("Failed to destroy {}: {}", masked.email, e);
+            }
+        }
+    }
+    finish_bulk(targets.len(), processed, failed);
+}
+
+fn prune(
+    creds: &Credentials,
+    older_than: u32,
+    unused: bool,
+    yes: bool,
+    bulk: &BulkArgs,
+    quiet: bool,
+) {
+    let config = require_config(creds);
+    let client = build_client(&config);
+    let spinner = spinner("Fetching masked emails…");
+    let emails = client.list(&config.account_id);
+    drop(spinner);
+    let emails = match emails {
+        Ok(emails) => emails,
+        Err(e) => handle_error("Failed to list masked emails", e),
+    };
+
+    let cutoff = Date::today().days_before(older_than);
+    // Aliases whose dates can't be read are never pruned
+    let targets: Vec<&MaskedEmail> = emails
+        .iter()
+        .filter(|e| e.id.is_some() && e.state.as_deref() != Some("deleted"))
+        .filter(|e| !unused || e.last_message_at.is_none())
+        .filter(|e| e.last_active_date().is_some_and(|date| date < cutoff))
+        .collect();
+
+    if targets.is_empty() {
+        if !quiet {
+            println!("No masked emails to prune.");
+        }
+        return;
+    }
+    if bulk.dry_run {
+        print_plan("destroyed", &targets);
+    } else if confirm_destroy(&targets, yes) {
+        destroy_all(&client, &config, &targets, bulk.fail_fast, quiet);
+    }
+}
+
+fn info(creds: &Credentials, email: String, json: bool) {
+    let config = require_config(creds);
+    let client = build_client(&config);
+    let masked = match client.get(&config.account_id, &email) {
+        Ok(masked) => masked,
+        Err(e) => handle_error("Error", e),
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&masked).unwrap());
+        return;
+    }
+    let fields = [
+        ("ID", masked.id.as_ref().map(|id| id.to_string())),
+        ("Email", Some(masked.email.clone())),
+        ("State", masked.state.clone()),
+        ("Domain", masked.for_domain.clone()),
+        ("Description", masked.description.clone()),
+        ("Created", masked.created_at.clone()),
+        ("Last message", masked.last_message_at.clone()),
+        ("URL", masked.url.clone()),
+        ("Created by", masked.created_by.clone()),
+    ];
+    for (name, value) in fields {
+        let value = value.filter(|v| !v.is_empty()).unwrap_or_else(|| "-".to_string());
+        println!("{:<14}{}", format!("{}:", name), value);
+    }
+}
+
+/// How long `masked test --wait` waits for delivery.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn test_send(creds: &Credentials, email: String, wait: bool, quiet: bool) {
+    let config = require_config(creds);
+    let client = build_client(&config);
+    let masked = match client.get(&config.account_id, &email) {
+        Ok(masked) => masked,
+        Err(e) => handle_error("Error", e),
+    };
+    // Pending aliases start forwarding with their first message
+    if let Some(state @ ("disabled" | "deleted")) = masked.state.as_deref() {
+        eprintln!("Error: {} is {}, so mail to it isn't forwarded.", masked.email, state);
+        std::process::exit(EXIT_USAGE);
+    }
+
+    let message = match client.send_test_message(&masked.email) {
+        Ok(message) => message,
+        Err(FastmailError::MissingCapability(urn)) => {
+            eprintln!("Error: Your token can't send mail ({} is missing).", urn);
+            eprintln!("Create a token with the 'Email submission' scope and run 'tmail login'.");
+            std::process::exit(EXIT_AUTH);
+        }
+        Err(e) => handle_error("Failed to send a test message", e),
+    };
+    if !quiet {
+        println!("Sent \"{}\" to {}", message.subject, masked.email);
+    }
+    if !wait {
+        return;
+    }
+
+    let spinner = spinner("Waiting for the test message to arrive…");
+    let start = Instant::now();
+    let mut delay = Duration::from_secs(1);
+    loop {
+        std::thread::sleep(delay);
+        match client.test_message_arrived(&message) {
+            Ok(true) => break,
+            Ok(false) => {}
+            Err(e) => {
+                drop(spinner);
+                handle_error("Failed to check for the test message", e)
+            }
+        }
+        if start.elapsed() >= DELIVERY_TIMEOUT {
+            drop(spinner);
+            eprintln!(
+                "Error: The test message hadn't arrived after {}s; check your filters and spam folder.",
+                DELIVERY_TIMEOUT.as_secs()
+            );
+            std::process::exit(EXIT_API);
+        }
+        delay = (delay * 2).min(Duration::from_secs(8));
+    }
+    drop(spinner);
+    if !quiet {
+        println!("Delivered after {}s.", start.elapsed().as_secs());
+    }
+}
+
+fn toggle(creds: &Credentials, email: String, quiet: bool) {
+    let config = require_config(creds);
+    let client = build_client(&config);
+
+    let (masked, id) = resolve_alias(&client, &config, &email);
+
+    let (result, new_state) = match masked.state.as_deref() {
+        Some("deleted") => {
+            eprintln!("Error: Masked email '{}' is deleted.", email);
+            eprintln!("Restore it before toggling.");
+            std::process::exit(EXIT_USAGE);
+        }
+        Some("enabled") => (client.disable(&config.account_id, &id), "disabled"),
+        _ => (client.enable(&config.account_id, &id), "enabled"),
+    };
+
+    match result {
+        Ok(()) => {
+            if quiet {
+                println!("{}", new_state);
+            } else {
+                println!("{}: {}", email, new_state);
+            }
+        }
+        Err(e) => handle_error("Failed to toggle masked email", e),
+    }
+}
+
+/// Create a replacement for `email` with the same description, domain and
+/// url, then archive or `destroy` the old one. The old alias is only
+/// touched once the new one exists.
+fn rotate(creds: &Credentials, email: String, destroy: bool, yes: bool, quiet: bool) {
+    let config = require_config(creds);
+    let client = build_client(&config);
+
+    let (masked, id) = resolve_alias(&client, &config, &email);
+    if masked.state.as_deref() == Some("deleted") {
+        eprintln!("Error: Masked email '{}' is deleted; there's nothing to rotate.", email);
+        std::process::exit(EXIT_USAGE);
+    }
+    if destroy && !yes {
+        if !prompt::is_interactive() {
+            eprintln!("Error: Refusing to destroy without confirmation. Pass --yes to proceed.");
+            std::process::exit(EXIT_USAGE);
+        }
+        let message = format!("Permanently destroy {} after creating its replacement?", email);
+        if !prompt::confirm(&message, false) {
+            eprintln!("Aborted.");
+            return;
+        }
+    }
+
+    let spec = CreateSpec {
+        description: masked.description.clone(),
+        for_domain: masked.for_domain.clone(),
+        url: masked.url.clone(),
+        email_prefix: masked.for_domain.as_deref().and_then(tmail::prefix_from_domain),
+        state: None,
+    };
+    let replacement = match client.create(&config.account_id, &spec) {
+        Ok(replacement) => replacement,
+        Err(e) => handle_error("Failed to create a replacement; the old address is unchanged", e),
+    };
+
+    let (retired, verb) = if destroy {
+        (client.destroy(&config.account_id, &id), "Destroyed")
+    } else {
+        (client.disable(&config.account_id, &id), "Archived")
+    };
+    println!("{}", replacement.email);
+    warn_retry_tag(&replacement);
+    match retired {
+        Ok(()) => {
+            if !quiet {
+                eprintln!("{}: {}", verb, email);
+            }
+        }
+        Err(e) => {
+            let context = format!(
+                "Created {} but failed to retire the old address",
+                replacement.email
+            );
+            handle_error(&context, e)
+        }
+    }
+}
+
+fn open(creds: &Credentials, email: String, print_url: bool) {
+    let config = require_config(creds);
+    let client = build_client(&config);
+
+    let emails = match client.list(&config.account_id) {
+        Ok(emails) => ListResult::from(emails),
+        Err(e) => handle_error("Failed to list masked emails", e),
+    };
+    match emails.find(&email) {
+        Some(masked) => match &masked.id {
+            Some(id) => eprintln!("Look for {} (id {})", masked.summary_line(), id),
+            None => eprintln!("Look for {}", masked.summary_line()),
         },
+        None => eprintln!(
+            "Note: could not resolve '{}'; opening the masked email settings page",
+            email
+        ),
+    }
+
+    let url = MASKED_EMAIL_SETTINGS_URL;
+    if print_url {
+        println!("{}", url);
+        return;
+    }
+    if let Err(e) = webbrowser::open(url) {
+        eprintln!("Could not open browser ({}). Visit: {}", e, url);
+        std::process::exit(EXIT_API);
+    }
+}
+
+fn import(creds: &Credentials, file: PathBuf, bulk: &BulkArgs, quiet: bool) {
+    let specs = match backup::read_backup(&file) {
+        Ok(specs) => specs,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(EXIT_USAGE);
+        }
+    };
+
+    let config = require_config(creds);
+    let client = build_client(&config);
+
+    let emails = match client.list(&config.account_id) {
+        Ok(emails) => emails,
+        Err(e) => handle_error("Failed to list masked emails", e),
+    };
+
+    // Addresses are server-assigned, so an alias counts as present when an
+    // existing non-deleted one has the same description and domain
+    let mut existing: HashSet<(String, String)> = emails
+        .iter()
+        .filter(|e| e.state.as_deref() != Some("deleted"))
+        .map(|e| {
+            (
+                e.description.clone().unwrap_or_default(),
+                e.for_domain.clone().unwrap_or_default(),
+            )
+        })
+        .collect();
+
+    let mut to_create = Vec::new();
+    // 1-based position in the backup of each entry in `to_create`
+    let mut rows = Vec::new();
+    let mut skipped = 0;
+    for (row, spec) in (1..).zip(specs) {
+        let key = (
+            spec.description.clone().unwrap_or_default(),
+            spec.for_domain.clone().unwrap_or_default(),
+        );
+        if existing.insert(key) {
+            to_create.push(spec);
+            rows.push(row);
+        } else {
+            skipped += 1;
+        }
+    }
+
+    if bulk.dry_run {
+        for (n, spec) in (1..).zip(&to_create) {
+            let domain = spec.for_domain.as_deref().unwrap_or("-");
+            let description = spec.description.as_deref().unwrap_or("-");
+            println!("{:>4}. {} — {}", n, domain, description);
+        }
+        println!(
+            "{} masked emails would be created; {} already present.",
+            to_create.len(),
+            skipped
+        );
+        return;
+    }
+
+    let mut created = 0;
+    let mut failed = 0;
+    let mut processed = 0;
+    if !to_create.is_empty() {
+        let results = bulk_results(
+            &to_create,
+            bulk.fail_fast,
+            || client.create_many(&config.account_id, &to_create),
+            |i, spec| {
+                client.create(&config.account_id, spec).map_err(|error| BatchItemError {
+                    index: i,
+                    spec: Box::new(spec.clone()),
+                    code: None,
+                    error,
+                })
+            },
+        );
+        let results = match results {
+            Ok(results) => results,
+            Err(e) => handle_error("Failed to import masked emails", e),
+        };
+        processed = results.len();
+        for result in results {
+            match result {
+                Ok(masked) => {
+                    created += 1;
+                    if !quiet {
+                        println!("Created: {}", masked.email);
+                    }
+                }
+                Err(e) => {
+                    failed += 1;
+                    let reason = e.code.clone().unwrap_or_else(|| e.error.to_string());
+                    eprintln!(
+                        "Row {} (description '{}') failed: {}",
+                        rows[e.index],
+                        e.spec.description.as_deref().unwrap_or(""),
+                        reason
+                    );
+                }
+            }
+        }
+    }
+
+    if !quiet {
+        println!("{} created, {} skipped", created, skipped);
+    }
+    if failed > 0 {
+        eprintln!("{} failed", failed);
+    }
+    finish_bulk(to_create.len(), processed, failed);
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    if let Some(path) = cli.config {
+        config::set_config_file(path);
+    }
+    let quiet = cli.quiet;
+    let color = prompt::use_color(cli.no_color);
+    let creds = Credentials {
+        token: cli
+            .token
+            .or_else(|| std::env::var("FASTMAIL_TOKEN").ok())
+            .filter(|t| !t.is_empty()),
+        account_id: cli.account_id.map(AccountId::from),
+    };
+
+    let json = match &cli.command {
+        Commands::Masked { command } => match command {
+            MaskedCommands::List(args) => args.json || args.json_lines,
+            MaskedCommands::Create(args) => args.json,
+            MaskedCommands::Search(args) => args.json,
+            MaskedCommands::Info { json, .. } => *json,
+            MaskedCommands::Report { json } => *json,
+            _ => false,
+        },
+        _ => false,
+    };
+    JSON_ERRORS.store(json, Ordering::Relaxed);
+    PROGRESS.store(!quiet && !json, Ordering::Relaxed);
+
+    match cli.command {
+        Commands::Login => login(quiet),
+        Commands::Whoami => whoami(&creds),
+        Commands::Version => version(),
+        Commands::Doctor => doctor(&creds),
+        Commands::Config { command } => match command {
+            ConfigCommands::Show => show_config(&creds),
+        },
+        Commands::Masked { command } => match command {
+            MaskedCommands::List(args) => list(&creds, args, quiet, color),
+            MaskedCommands::Create(args) => create(&creds, args, quiet),
+            MaskedCommands::Count { quota } => count(&creds, quota, quiet),
+            MaskedCommands::Search(args) => search(&creds, args, quiet, color),
+            MaskedCommands::Report { json } => report(&creds, json, quiet),
+            MaskedCommands::Delete { email } => delete(&creds, email, quiet),
+            MaskedCommands::Disable { domain, yes, bulk } => {
+                set_domain_state(&creds, &domain, "disabled", yes, &bulk, quiet)
+            }
+            MaskedCommands::Enable { domain, yes, bulk } => {
+                set_domain_state(&creds, &domain, "enabled", yes, &bulk, quiet)
+            }
+            MaskedCommands::Destroy { emails, yes, bulk } => {
+                destroy(&creds, emails, yes, &bulk, quiet)
+            }
+            MaskedCommands::Prune {
+                older_than,
+                unused,
+                yes,
+                bulk,
+            } => prune(&creds, older_than, unused, yes, &bulk, quiet),
+            MaskedCommands::Info { email, json } => info(&creds, email, json),
+            MaskedCommands::Toggle { email } => toggle(&creds, email, quiet),
+            MaskedCommands::Test { email, wait } => test_send(&creds, email, wait, quiet),
+            // `--archive` is the default spelled out; clap rejects it with `--destroy`
+            MaskedCommands::Rotate {
+                email,
+                destroy,
+                yes,
+                ..
+            } => rotate(&creds, email, destroy, yes, quiet),
+            MaskedCommands::Edit {
+                email,
+                description,
+                clear_description,
+                website,
+            } => edit(&creds, email, description, clear_description, website, quiet),
+            MaskedCommands::Open { email, print_url } => open(&creds, email, print_url),
+            MaskedCommands::Import { file, bulk } => import(&creds, file, &bulk, quiet),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes_follow_http_status() {
+        let status = |code| FastmailError::Status(code, String::new());
+        assert_eq!(exit_code(&status(500)), EXIT_API);
+        assert_eq!(exit_code(&status(429)), EXIT_API);
+        assert_eq!(exit_code(&FastmailError::Auth(401, String::new())), EXIT_AUTH);
+        assert_eq!(exit_code(&FastmailError::Auth(403, String::new())), EXIT_AUTH);
+    }
+
+    #[test]
+    fn test_rotate_archive_conflicts_with_destroy() {
+        let parse = |flags: &[&str]| {
+            let args = ["tmail", "masked", "rotate", "abc@fastmail.com"];
+            Cli::try_parse_from(args.iter().chain(flags))
+        };
+        let err = parse(&["--archive", "--destroy"]).err().unwrap();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+        assert!(parse(&["--archive"]).is_ok());
+        assert!(parse(&["--destroy"]).is_ok());
     }
 }
@@ -0,0 +1,510 @@
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+use tmail::{MaskedEmail, Timestamp};
+
+/// Write `content` to `path` via a temporary file in the same directory and
+/// a rename, so a crash never leaves a half-written file behind.
+pub fn write_atomic(path: &Path, content: &str) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "output path has no file name")
+    })?;
+    let tmp = dir.join(format!(
+        ".{}.tmp-{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+
+    let result = fs::File::create(&tmp).and_then(|mut file| {
+        file.write_all(content.as_bytes())?;
+        file.sync_all()
+    });
+    let result = result.and_then(|()| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// Escape a free-text field for tab-separated output so each record stays
+/// on one line with a fixed number of columns.
+pub fn escape_tsv(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// One `list --porcelain` record: email, state, domain, description,
+/// created, last message, id and created-by, tab-separated and escaped
+/// with `escape_tsv`. This layout is a stable contract; only append fields.
+pub fn porcelain_row(email: &MaskedEmail) -> String {
+    let fields = [
+        Some(email.email.as_str()),
+        email.state.as_deref(),
+        email.for_domain.as_deref(),
+        email.description.as_deref(),
+        email.created_at.as_deref(),
+        email.last_message_at.as_deref(),
+        email.id.as_ref().map(|id| id.as_ref()),
+        email.created_by.as_deref(),
+    ];
+    fields
+        .iter()
+        .map(|field| escape_tsv(field.unwrap_or("")))
+        .collect::<Vec<_>>()
+        .join("\t")
+}
+
+/// A POSIX `export NAME='value'` line for `eval`, or `None` when `value`
+/// holds anything beyond the characters Fastmail uses in addresses and ids.
+pub fn shell_export(name: &str, value: &str) -> Option<String> {
+    let safe = !value.is_empty()
+        && value.chars().all(|c| c.is_ascii_alphanumeric() || "@._+-".contains(c));
+    safe.then(|| format!("export {}='{}'", name, value))
+}
+
+/// A `mailto:` URL for `address`, percent-encoding everything but
+/// unreserved characters and the `@`.
+pub fn mailto_url(address: &str) -> String {
+    let mut url = String::from("mailto:");
+    for byte in address.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~@".contains(&byte) {
+            url.push(byte as char);
+        } else {
+            url.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    url
+}
+
+/// Placeholders accepted by `Template`, which are also the `SortOrder` keys.
+const PLACEHOLDERS: [&str; 6] = [
+    "email",
+    "description",
+    "domain",
+    "state",
+    "created",
+    "last_message",
+];
+
+#[derive(Clone)]
+enum Segment {
+    Text(String),
+    Field(&'static str),
+}
+
+/// A per-record output format such as `{email},{domain}`; `{{` and `}}`
+/// stand for literal braces.
+#[derive(Clone)]
+pub struct Template(Vec<Segment>);
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments = Vec::new();
+        let mut text = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("unclosed '{{{}'", name)),
+                        }
+                    }
+                    let Some(field) = PLACEHOLDERS.iter().find(|p| **p == name) else {
+                        return Err(format!(
+                            "unknown placeholder '{{{}}}'; valid ones are {}",
+                            name,
+                            PLACEHOLDERS.map(|p| format!("{{{}}}", p)).join(", ")
+                        ));
+                    };
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    segments.push(Segment::Field(field));
+                }
+                '}' => return Err("unmatched '}'; write '}}' for a literal brace".to_string()),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+        Ok(Template(segments))
+    }
+}
+
+impl Template {
+    /// Fill in the placeholders from `email`, leaving missing fields empty.
+    pub fn render(&self, email: &MaskedEmail) -> String {
+        self.0
+            .iter()
+            .map(|segment| match segment {
+                Segment::Text(text) => text.as_str(),
+                Segment::Field(field) => field_value(email, field),
+            })
+            .collect()
+    }
+}
+
+/// The value of one of the `PLACEHOLDERS` fields, empty when missing.
+fn field_value<'a>(email: &'a MaskedEmail, field: &str) -> &'a str {
+    match field {
+        "email" => email.email.as_str(),
+        "description" => email.description.as_deref().unwrap_or(""),
+        "domain" => email.for_domain.as_deref().unwrap_or(""),
+        "state" => email.state.as_deref().unwrap_or(""),
+        "created" => email.created_at.as_deref().unwrap_or(""),
+        "last_message" => email.last_message_at.as_deref().unwrap_or(""),
+        _ => unreachable!("fields are checked when parsing"),
+    }
+}
+
+/// Comma-separated sort keys such as `domain,created`, compared in turn.
+#[derive(Clone)]
+pub struct SortOrder(Vec<&'static str>);
+
+impl FromStr for SortOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|key| {
+                let key = key.trim();
+                PLACEHOLDERS.iter().find(|p| **p == key).copied().ok_or_else(|| {
+                    format!(
+                        "unknown sort key '{}'; valid ones are {}",
+                        key,
+                        PLACEHOLDERS.join(", ")
+                    )
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map(SortOrder)
+    }
+}
+
+/// One email's value for a sort key. A key's values are all times or all
+/// text, and missing ones sort after either.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum SortValue {
+    Time(Timestamp),
+    /// Lowercased, so case is ignored
+    Text(String),
+    Missing,
+}
+
+impl SortOrder {
+    fn key(&self, email: &MaskedEmail) -> Vec<SortValue> {
+        self.0
+            .iter()
+            .map(|key| {
+                let value = field_value(email, key);
+                let time = match *key {
+                    "created" => Some(email.created_timestamp()),
+                    "last_message" => Some(email.last_message_timestamp()),
+                    _ => None,
+                };
+                match time {
+                    Some(Some(time)) => SortValue::Time(time),
+                    // An unparseable timestamp can't be placed, like a missing one
+                    Some(None) => SortValue::Missing,
+                    None if value.is_empty() => SortValue::Missing,
+                    None => SortValue::Text(value.to_lowercase()),
+                }
+            })
+            .collect()
+    }
+
+    /// Sort by each key in turn, working out every email's keys once.
+    /// Stable, so ties keep their current order.
+    pub fn sort(&self, emails: &mut [&MaskedEmail], reverse: bool) {
+        if reverse {
+            emails.sort_by_cached_key(|e| Reverse(self.key(e)));
+        } else {
+            emails.sort_by_cached_key(|e| self.key(e));
+        }
+    }
+}
+
+/// How many domains `masked report` lists.
+const TOP_DOMAINS: usize = 5;
+
+/// Overview of an account's masked emails for `masked report`. Deleted
+/// aliases are left out, as they can no longer receive mail.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub total: usize,
+    pub by_state: BTreeMap<String, usize>,
+    /// Aliases that have received at least one message
+    pub received_mail: usize,
+    pub oldest: Option<DatedAlias>,
+    pub newest: Option<DatedAlias>,
+    /// Domains with the most aliases, most first
+    pub top_domains: Vec<DomainCount>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DatedAlias {
+    pub email: String,
+    pub created: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DomainCount {
+    pub domain: String,
+    pub count: usize,
+}
+
+impl Report {
+    pub fn new(emails: &[MaskedEmail]) -> Self {
+        let live: Vec<&MaskedEmail> = emails
+            .iter()
+            .filter(|e| e.state.as_deref() != Some("deleted"))
+            .collect();
+
+        let mut by_state = BTreeMap::new();
+        let mut domains: BTreeMap<String, usize> = BTreeMap::new();
+        for email in &live {
+            let state = email.state.as_deref().unwrap_or("unknown").to_string();
+            *by_state.entry(state).or_default() += 1;
+            let domain = email.for_domain.as_deref().unwrap_or("");
+            let domain = domain.trim().trim_end_matches('.').to_lowercase();
+            if !domain.is_empty() {
+                *domains.entry(domain).or_default() += 1;
+            }
+        }
+        let mut top_domains: Vec<DomainCount> = domains
+            .into_iter()
+            .map(|(domain, count)| DomainCount { domain, count })
+            .collect();
+        // Stable, so domains with equal counts stay alphabetical
+        top_domains.sort_by_key(|d| std::cmp::Reverse(d.count));
+        top_domains.truncate(TOP_DOMAINS);
+
+        let dated = || live.iter().filter_map(|e| Some((e.created_date()?, *e)));
+        let entry = |found: Option<(tmail::Date, &MaskedEmail)>| {
+            found.map(|(date, e)| DatedAlias {
+                email: e.email.clone(),
+                created: date.to_string(),
+            })
+        };
+        Report {
+            total: live.len(),
+            by_state,
+            received_mail: live.iter().filter(|e| e.last_message_at.is_some()).count(),
+            oldest: entry(dated().min_by_key(|(date, _)| *date)),
+            newest: entry(dated().max_by_key(|(date, _)| *date)),
+            top_domains,
+        }
+    }
+
+    /// The report as aligned text, one newline-terminated line per figure.
+    pub fn render(&self) -> String {
+        let states: Vec<String> = self
+            .by_state
+            .iter()
+            .map(|(state, count)| format!("{} {}", count, state))
+            .collect();
+        let percent = self.received_mail * 100 / self.total.max(1);
+        let mut lines = vec![
+            format!("Aliases:        {} ({})", self.total, states.join(", ")),
+            format!("Received mail:  {} ({}%)", self.received_mail, percent),
+        ];
+        for (label, alias) in [("Oldest:", &self.oldest), ("Newest:", &self.newest)] {
+            if let Some(alias) = alias {
+                lines.push(format!("{:<15} {}  {}", label, alias.created, alias.email));
+            }
+        }
+        if !self.top_domains.is_empty() {
+            lines.push("Top domains:".to_string());
+            let width = self.top_domains[0].count.to_string().len();
+            for domain in &self.top_domains {
+                lines.push(format!("  {:>width$}  {}", domain.count, domain.domain));
+            }
+        }
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_porcelain_row() {
+        let email: MaskedEmail = serde_json::from_value(serde_json::json!({
+            "id": "m1",
+            "email": "abc@fastmail.com",
+            "state": "enabled",
+            "description": "tab\there",
+            "createdAt": "2024-01-15T10:30:00Z"
+        }))
+        .unwrap();
+        assert_eq!(
+            porcelain_row(&email),
+            "abc@fastmail.com\tenabled\t\ttab\\there\t2024-01-15T10:30:00Z\t\tm1\t"
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let emails: Vec<MaskedEmail> = serde_json::from_value(serde_json::json!([
+            {"email": "a@fm.com", "state": "enabled", "forDomain": "shop.com",
+             "createdAt": "2021-05-01T00:00:00Z", "lastMessageAt": "2024-01-01T00:00:00Z"},
+            {"email": "b@fm.com", "state": "disabled", "forDomain": "Shop.com.",
+             "createdAt": "2019-02-03T00:00:00Z"},
+            {"email": "c@fm.com", "state": "enabled", "forDomain": "news.org",
+             "createdAt": "2023-11-30T00:00:00Z"},
+            {"email": "d@fm.com", "state": "deleted", "forDomain": "news.org",
+             "createdAt": "2018-01-01T00:00:00Z"}
+        ]))
+        .unwrap();
+        let report = Report::new(&emails);
+        assert_eq!(report.total, 3);
+        assert_eq!(report.received_mail, 1);
+        assert_eq!(report.oldest.as_ref().unwrap().email, "b@fm.com");
+        assert_eq!(report.newest.as_ref().unwrap().created, "2023-11-30");
+        assert_eq!(
+            report.render(),
+            "Aliases:        3 (1 disabled, 2 enabled)\n\
+             Received mail:  1 (33%)\n\
+             Oldest:         2019-02-03  b@fm.com\n\
+             Newest:         2023-11-30  c@fm.com\n\
+             Top domains:\n  \
+             2  shop.com\n  \
+             1  news.org\n"
+        );
+
+        let empty = Report::new(&[]);
+        assert_eq!(empty.total, 0);
+        assert!(empty.oldest.is_none() && empty.top_domains.is_empty());
+    }
+
+    #[test]
+    fn test_shell_export() {
+        assert_eq!(
+            shell_export("TMAIL_EMAIL", "abc.def@fastmail.com").as_deref(),
+            Some("export TMAIL_EMAIL='abc.def@fastmail.com'")
+        );
+        assert!(shell_export("TMAIL_ID", "").is_none());
+        assert!(shell_export("TMAIL_ID", "m1'; rm -rf ~").is_none());
+        assert!(shell_export("TMAIL_ID", "$(id)").is_none());
+    }
+
+    #[test]
+    fn test_mailto_url() {
+        assert_eq!(mailto_url("abc.def@fastmail.com"), "mailto:abc.def@fastmail.com");
+        assert_eq!(mailto_url("a+b c?@x.com"), "mailto:a%2Bb%20c%3F@x.com");
+    }
+
+    #[test]
+    fn test_sort_order() {
+        let email = |email: &str, domain: Option<&str>, created: &str| -> MaskedEmail {
+            serde_json::from_value(serde_json::json!({
+                "email": email,
+                "forDomain": domain,
+                "createdAt": created
+            }))
+            .unwrap()
+        };
+        let emails = [
+            email("a@x.com", None, "2024-01-01T00:00:00Z"),
+            email("b@x.com", Some("B.com"), "2024-03-01T00:00:00Z"),
+            email("c@x.com", Some("a.com"), "2024-02-01T00:00:00Z"),
+            email("d@x.com", Some("b.com"), "2024-01-01T00:00:00Z"),
+            email("e@x.com", Some("a.com"), "bogus"),
+            email("f@x.com", Some("a.com"), "2024-02-01T09:30:00Z"),
+        ];
+        let sorted = |keys: &str, reverse| -> Vec<&str> {
+            let order: SortOrder = keys.parse().unwrap();
+            let mut refs: Vec<&MaskedEmail> = emails.iter().collect();
+            order.sort(&mut refs, reverse);
+            refs.iter().map(|e| e.email.as_str()).collect()
+        };
+        assert_eq!(
+            sorted("domain, created", false),
+            ["c@x.com", "f@x.com", "e@x.com", "d@x.com", "b@x.com", "a@x.com"]
+        );
+        // Equal dates keep their order; undated entries go last
+        assert_eq!(
+            sorted("created", false),
+            ["a@x.com", "d@x.com", "c@x.com", "f@x.com", "b@x.com", "e@x.com"]
+        );
+        assert_eq!(sorted("created", true)[..2], ["e@x.com", "b@x.com"]);
+
+        let err = "domain,site".parse::<SortOrder>().err().unwrap();
+        assert!(err.contains("'site'") && err.contains("last_message"), "{}", err);
+    }
+
+    #[test]
+    fn test_template_render() {
+        let email: MaskedEmail = serde_json::from_value(serde_json::json!({
+            "email": "abc@fastmail.com",
+            "forDomain": "example.com",
+            "state": "enabled"
+        }))
+        .unwrap();
+        let template: Template = "{email};{domain};{description} {{x}}".parse().unwrap();
+        assert_eq!(template.render(&email), "abc@fastmail.com;example.com; {x}");
+
+        let err = "{email} {site}".parse::<Template>().err().unwrap();
+        assert!(err.contains("'{site}'"), "{}", err);
+        assert!(err.contains("{last_message}"), "{}", err);
+        assert!("{email".parse::<Template>().is_err());
+        assert!("email}".parse::<Template>().is_err());
+    }
+
+    #[test]
+    fn test_escape_tsv_keeps_rows_on_one_line() {
+        let description = escape_tsv("line1\nline2\ttab\\");
+        assert_eq!(description, "line1\\nline2\\ttab\\\\");
+
+        let row = ["abc@fastmail.com", "2024-01-15", "example.com", &description].join("\t");
+        assert_eq!(row.lines().count(), 1);
+        assert_eq!(row.split('\t').count(), 4);
+    }
+
+    #[test]
+    fn test_write_atomic_replaces_file() {
+        let dir = std::env::temp_dir().join(format!("tmail-output-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("backup.json");
+        fs::write(&path, "old").unwrap();
+
+        write_atomic(&path, "new").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        // Only the target is left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,11 +1,27 @@
-use inquire::Text;
-use std::io::IsTerminal;
+use indicatif::ProgressBar;
+use inquire::{Confirm, Text};
+use std::io::{self, IsTerminal, Read};
+use std::path::Path;
+use std::time::Duration;
 
 pub fn is_interactive() -> bool {
     std::io::stdin().is_terminal()
 }
 
-pub fn prompt_text(prompt: &str, help: Option<&str>, placeholder: Option<&str>) -> Option<String> {
+/// Whether to colorize output: not disabled by `--no-color` or a non-empty
+/// `NO_COLOR`, and stdout is a terminal rather than a pipe.
+pub fn use_color(no_color: bool) -> bool {
+    !no_color
+        && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+        && std::io::stdout().is_terminal()
+}
+
+pub fn prompt_text(
+    prompt: &str,
+    help: Option<&str>,
+    placeholder: Option<&str>,
+    initial: Option<&str>,
+) -> Option<String> {
     let mut builder = Text::new(prompt);
     if let Some(h) = help {
         builder = builder.with_help_message(h);
@@ -13,5 +29,92 @@ pub fn prompt_text(prompt: &str, help: Option<&str>, placeholder: Option<&str>)
     if let Some(p) = placeholder {
         builder = builder.with_placeholder(p);
     }
+    if let Some(i) = initial {
+        builder = builder.with_initial_value(i);
+    }
     builder.prompt().ok().filter(|s| !s.is_empty())
 }
+
+pub fn confirm(prompt: &str, default: bool) -> bool {
+    Confirm::new(prompt)
+        .with_default(default)
+        .prompt()
+        .unwrap_or(false)
+}
+
+/// A spinner on stderr while a slow operation runs, cleared when dropped so
+/// it never mixes with the results. Does nothing unless `enabled` and
+/// stderr is a terminal.
+pub struct Spinner(Option<ProgressBar>);
+
+impl Spinner {
+    pub fn start(message: &str, enabled: bool) -> Self {
+        if !enabled || !io::stderr().is_terminal() {
+            return Spinner(None);
+        }
+        let bar = ProgressBar::new_spinner();
+        bar.set_message(message.to_string());
+        bar.enable_steady_tick(Duration::from_millis(100));
+        Spinner(Some(bar))
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        if let Some(bar) = &self.0 {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+/// Resolve a text option that can be given inline, as `-` to read `stdin`,
+/// or from a file, dropping one trailing newline from piped or file input.
+pub fn read_text_arg(
+    value: Option<String>,
+    file: Option<&Path>,
+    mut stdin: impl Read,
+) -> io::Result<Option<String>> {
+    let mut text = match (value, file) {
+        (_, Some(path)) => std::fs::read_to_string(path)?,
+        (Some(value), None) if value == "-" => {
+            let mut text = String::new();
+            stdin.read_to_string(&mut text)?;
+            text
+        }
+        (value, None) => return Ok(value),
+    };
+    if text.ends_with('\n') {
+        text.pop();
+        if text.ends_with('\r') {
+            text.pop();
+        }
+    }
+    Ok(Some(text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_text_arg_from_stdin() {
+        let stdin = "generated\ndescription\n".as_bytes();
+        let text = read_text_arg(Some("-".to_string()), None, stdin).unwrap();
+        assert_eq!(text.as_deref(), Some("generated\ndescription"));
+
+        let inline = read_text_arg(Some("shop".to_string()), None, io::empty()).unwrap();
+        assert_eq!(inline.as_deref(), Some("shop"));
+        assert_eq!(read_text_arg(None, None, io::empty()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_read_text_arg_from_file() {
+        let path = std::env::temp_dir().join(format!("tmail-desc-{}", std::process::id()));
+        std::fs::write(&path, "from a file\r\n").unwrap();
+        let text = read_text_arg(None, Some(&path), io::empty()).unwrap();
+        assert_eq!(text.as_deref(), Some("from a file"));
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(read_text_arg(None, Some(&path), io::empty()).is_err());
+    }
+}